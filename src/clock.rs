@@ -0,0 +1,68 @@
+use std::sync::Arc;
+use std::time::Instant;
+use time::OffsetDateTime;
+
+/// The source of time for [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) and
+/// [`JsonStorageLayer`](crate::JsonStorageLayer).
+///
+/// The system clock is used by default: provide your own implementation, shared by both
+/// layers, to get reproducible records in tests.
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use std::time::Instant;
+/// use time::OffsetDateTime;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Clock, JsonStorageLayer};
+///
+/// struct FrozenClock {
+///     instant: Instant,
+/// }
+///
+/// impl Clock for FrozenClock {
+///     fn now(&self) -> OffsetDateTime {
+///         OffsetDateTime::UNIX_EPOCH
+///     }
+///
+///     fn instant(&self) -> Instant {
+///         self.instant
+///     }
+/// }
+///
+/// let clock = Arc::new(FrozenClock { instant: Instant::now() });
+/// let storage_layer = JsonStorageLayer::default().with_clock(clock.clone());
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .clock(clock)
+///     .build()
+///     .unwrap();
+/// ```
+pub trait Clock: Send + Sync + 'static {
+    /// The current wall-clock time, used for the `time` field of records.
+    fn now(&self) -> OffsetDateTime;
+
+    /// The current monotonic time, used to measure the duration of spans.
+    fn instant(&self) -> Instant;
+}
+
+/// The system clock, via [`OffsetDateTime::now_utc`] and [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> OffsetDateTime {
+        OffsetDateTime::now_utc()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+impl<C: Clock + ?Sized> Clock for Arc<C> {
+    fn now(&self) -> OffsetDateTime {
+        (**self).now()
+    }
+
+    fn instant(&self) -> Instant {
+        (**self).instant()
+    }
+}
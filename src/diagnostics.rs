@@ -0,0 +1,148 @@
+use std::fmt;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// An issue [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) ran into while formatting
+/// or writing a record.
+///
+/// The layer cannot report these issues via `tracing` itself: it would re-enter the subscriber
+/// it is part of.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Diagnostic<'a> {
+    /// A field was skipped because its key clashes with a field populated by the layer.
+    ReservedFieldDropped { key: &'a str },
+    /// A record could not be serialized and has been discarded.
+    SerializationFailed(&'a std::io::Error),
+    /// A serialized record could not be written.
+    WriteFailed(&'a std::io::Error),
+    /// A serialized record could not be written to the fallback writer either.
+    FallbackWriteFailed(&'a std::io::Error),
+    /// A record has been lost: it could not be serialized or written anywhere.
+    RecordDropped,
+}
+
+impl fmt::Display for Diagnostic<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Diagnostic::ReservedFieldDropped { key } => write!(
+                f,
+                "{} is a reserved field in the bunyan log format. Skipping it.",
+                key
+            ),
+            Diagnostic::SerializationFailed(e) => write!(f, "failed to serialize a record: {}", e),
+            Diagnostic::WriteFailed(e) => write!(f, "failed to write a record: {}", e),
+            Diagnostic::FallbackWriteFailed(e) => {
+                write!(f, "failed to write a record to the fallback writer: {}", e)
+            }
+            Diagnostic::RecordDropped => write!(f, "a record has been dropped"),
+        }
+    }
+}
+
+/// Counters of the [`Diagnostic`]s reported by a
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer).
+///
+/// Get hold of them via [`BunyanFormattingLayer::diagnostics`](crate::BunyanFormattingLayer::diagnostics)
+/// before handing the layer over to a subscriber.
+#[derive(Debug, Default)]
+pub struct Diagnostics {
+    dropped_fields: AtomicU64,
+    serialization_errors: AtomicU64,
+    write_errors: AtomicU64,
+    fallback_write_errors: AtomicU64,
+    dropped_records: AtomicU64,
+}
+
+impl Diagnostics {
+    /// The number of fields skipped because their keys clash with reserved fields.
+    pub fn dropped_fields(&self) -> u64 {
+        self.dropped_fields.load(Ordering::Relaxed)
+    }
+
+    /// The number of records discarded because they could not be serialized.
+    pub fn serialization_errors(&self) -> u64 {
+        self.serialization_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of records that could not be written.
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of records that could not be written to the fallback writer either.
+    pub fn fallback_write_errors(&self) -> u64 {
+        self.fallback_write_errors.load(Ordering::Relaxed)
+    }
+
+    /// The number of records lost for good, because they could not be serialized or written
+    /// anywhere.
+    pub fn dropped_records(&self) -> u64 {
+        self.dropped_records.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn record(&self, diagnostic: &Diagnostic<'_>) {
+        let counter = match diagnostic {
+            Diagnostic::ReservedFieldDropped { .. } => &self.dropped_fields,
+            Diagnostic::SerializationFailed(_) => &self.serialization_errors,
+            Diagnostic::WriteFailed(_) => &self.write_errors,
+            Diagnostic::FallbackWriteFailed(_) => &self.fallback_write_errors,
+            Diagnostic::RecordDropped => &self.dropped_records,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Gets notified of every [`Diagnostic`] reported by a
+/// [`BunyanFormattingLayer`](crate::BunyanFormattingLayer).
+///
+/// Handlers must not emit `tracing` events or spans: they would re-enter the subscriber.
+///
+/// It is implemented for closures taking a `&Diagnostic<'_>`:
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Diagnostic};
+///
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .diagnostic_handler(|diagnostic: &Diagnostic<'_>| eprintln!("{}", diagnostic))
+///     .build()
+///     .expect("Invalid configuration");
+/// ```
+pub trait DiagnosticHandler: Send + Sync + 'static {
+    fn handle(&self, diagnostic: &Diagnostic<'_>);
+}
+
+impl<F> DiagnosticHandler for F
+where
+    F: Fn(&Diagnostic<'_>) + Send + Sync + 'static,
+{
+    fn handle(&self, diagnostic: &Diagnostic<'_>) {
+        self(diagnostic)
+    }
+}
+
+/// A [`DiagnosticHandler`] writing one plain-text line per [`Diagnostic`] to a side writer,
+/// e.g. `std::io::stderr`.
+pub struct DiagnosticWriter<W> {
+    make_writer: W,
+}
+
+impl<W> DiagnosticWriter<W> {
+    pub fn new(make_writer: W) -> Self {
+        Self { make_writer }
+    }
+}
+
+impl<W> DiagnosticHandler for DiagnosticWriter<W>
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    fn handle(&self, diagnostic: &Diagnostic<'_>) {
+        // There is nobody left to report a failure to.
+        let _ = writeln!(
+            self.make_writer.make_writer(),
+            "tracing-bunyan-formatter: {}",
+            diagnostic
+        );
+    }
+}
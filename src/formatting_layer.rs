@@ -1,53 +1,132 @@
+use crate::clock::{Clock, SystemClock};
+use crate::diagnostics::{Diagnostic, DiagnosticHandler, Diagnostics};
+use crate::level::{BunyanLevel, DefaultLevelMapper, LevelMapper};
+use crate::message::{DefaultMessageFormatter, Message, MessageFormatter};
+#[cfg(feature = "otel")]
+use crate::otel::OtelIds;
+use crate::span_records::{
+    EmittedEnters, EnterExitLimiter, EnterExitRecords, SpanRecordFilter, SpanRecords,
+};
 use crate::storage_layer::JsonStorage;
-use serde::ser::{SerializeMap, Serializer};
+use crate::timestamp::{TimestampFormat, Timestamper};
+use crate::writer::RecordWriter;
+use serde::ser::{Serialize, SerializeMap, Serializer};
+use serde_json::ser::{CompactFormatter, Compound};
 use serde_json::Value;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt;
 use std::io::Write;
+use std::sync::Arc;
 use tracing::{Event, Id, Subscriber};
-use tracing_core::metadata::Level;
+use tracing_core::metadata::Metadata;
 use tracing_core::span::Attributes;
-use tracing_log::AsLog;
 use tracing_subscriber::fmt::MakeWriter;
 use tracing_subscriber::layer::Context;
-use tracing_subscriber::registry::SpanRef;
+use tracing_subscriber::registry::{Extensions, SpanRef};
 use tracing_subscriber::Layer;
-use time::format_description::well_known::Rfc3339;
 
 /// Keys for core fields of the Bunyan format (https://github.com/trentm/node-bunyan#core-fields)
 const BUNYAN_VERSION: &str = "v";
 const LEVEL: &str = "level";
+const LEVEL_NAME: &str = "level_name";
 const NAME: &str = "name";
 const HOSTNAME: &str = "hostname";
 const PID: &str = "pid";
 const TIME: &str = "time";
 const MESSAGE: &str = "msg";
-const _SOURCE: &str = "src";
+const SOURCE: &str = "src";
+
+/// Keys for the identifiers of the span a record belongs to
+const SPAN_ID: &str = "span_id";
+const PARENT_SPAN_ID: &str = "parent_span_id";
+const TRACE_ID: &str = "trace_id";
 
 const BUNYAN_RESERVED_FIELDS: [&str; 7] =
     [BUNYAN_VERSION, LEVEL, NAME, HOSTNAME, PID, TIME, MESSAGE];
 
-/// Convert from log levels to Bunyan's levels.
-fn to_bunyan_level(level: &Level) -> u16 {
-    match level.as_log() {
-        log::Level::Error => 50,
-        log::Level::Warn => 40,
-        log::Level::Info => 30,
-        log::Level::Debug => 20,
-        log::Level::Trace => 10,
+/// The map serializer the fields of a record (other than the constant ones) are written to.
+type RecordSerializer<'a, 'b> = Compound<'a, &'b mut Vec<u8>, CompactFormatter>;
+
+/// Buffers growing beyond this capacity are not kept around for the next record.
+const MAX_RETAINED_BUFFER_CAPACITY: usize = 64 * 1024;
+
+thread_local! {
+    /// The buffer reused by all records serialized on a thread, to avoid allocating one
+    /// per record.
+    static BUFFER: RefCell<Vec<u8>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Run `f` with an empty buffer, reusing the thread-local one when possible.
+fn with_buffer(f: impl FnOnce(&mut Vec<u8>)) {
+    let mut f = Some(f);
+    let _ = BUFFER.try_with(|buffer| {
+        // The buffer is already in use if a record is emitted while emitting another one,
+        // e.g. by a writer instrumented with `tracing`.
+        if let Ok(mut buffer) = buffer.try_borrow_mut() {
+            buffer.clear();
+            if let Some(f) = f.take() {
+                f(&mut buffer);
+            }
+            if buffer.capacity() > MAX_RETAINED_BUFFER_CAPACITY {
+                *buffer = Vec::new();
+            }
+        }
+    });
+    // The thread-local buffer is not available (in use or already destroyed).
+    if let Some(f) = f {
+        f(&mut Vec::new());
     }
 }
 
+/// Serialize the core fields which never change, leaving the JSON object open.
+fn serialize_constant_fields(name: &str, hostname: &str, pid: u32) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let result: Result<(), serde_json::Error> = (|| {
+        let mut serializer = serde_json::Serializer::new(&mut buffer);
+        let mut map_serializer = serializer.serialize_map(None)?;
+        map_serializer.serialize_entry(BUNYAN_VERSION, &0)?;
+        map_serializer.serialize_entry(NAME, name)?;
+        map_serializer.serialize_entry(HOSTNAME, hostname)?;
+        map_serializer.serialize_entry(PID, &pid)?;
+        // We leave the closing brace out by not calling `end`.
+        Ok(())
+    })();
+    result.expect("Serializing strings and integers to a `Vec` cannot fail");
+    buffer
+}
+
 /// This layer is exclusively concerned with formatting information using the [Bunyan format](https://github.com/trentm/node-bunyan).
 /// It relies on the upstream `JsonStorageLayer` to get access to the fields attached to
 /// each span.
 pub struct BunyanFormattingLayer<W: for<'a> MakeWriter<'a> + 'static> {
     make_writer: W,
-    pid: u32,
-    hostname: String,
-    bunyan_version: u8,
-    name: String,
-    default_fields: HashMap<String, Value>,
+    /// The core fields which never change (`v`, `name`, `hostname`, `pid`), serialized once and
+    /// for all at construction. See [`BunyanFormattingLayer::serialize_record`].
+    constant_fields: Vec<u8>,
+    /// Sorted by key, to emit them in a consistent order.
+    default_fields: Vec<(String, Value)>,
+    nested_src: bool,
+    span_ids: bool,
+    level_mapper: Box<dyn LevelMapper>,
+    level_name: bool,
+    timestamper: Timestamper,
+    clock: Box<dyn Clock>,
+    span_records: SpanRecords,
+    enter_exit: EnterExitLimiter,
+    span_record_filter: Option<Box<dyn SpanRecordFilter>>,
+    span_records_own_fields_only: bool,
+    live_inherited_fields: bool,
+    error_backtraces: bool,
+    message_formatter: Box<dyn MessageFormatter>,
+    field_collision_policy: FieldCollisionPolicy,
+    field_order: FieldOrder,
+    diagnostics: Arc<Diagnostics>,
+    diagnostic_handler: Option<Box<dyn DiagnosticHandler>>,
+    fallback_writer: Option<Box<dyn RecordWriter>>,
+    #[cfg(feature = "otel")]
+    otel_context: bool,
 }
 
 impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
@@ -74,80 +153,353 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
         Self::with_default_fields(name, make_writer, HashMap::new())
     }
 
-    pub fn with_default_fields(name: String, make_writer: W, default_fields: HashMap<String, Value>) -> Self {
+    /// Create a new `BunyanFormattingLayer` which will attach `default_fields` to every
+    /// formatted record.
+    ///
+    /// Default fields whose keys clash with the Bunyan core fields are skipped.
+    /// Use [`BunyanFormattingLayer::builder`] if you'd rather get an error.
+    pub fn with_default_fields(
+        name: String,
+        make_writer: W,
+        default_fields: HashMap<String, Value>,
+    ) -> Self {
         Self {
             make_writer,
-            name,
-            pid: std::process::id(),
-            hostname: gethostname::gethostname().to_string_lossy().into_owned(),
-            bunyan_version: 0,
-            default_fields,
+            constant_fields: serialize_constant_fields(
+                &name,
+                &gethostname::gethostname().to_string_lossy(),
+                std::process::id(),
+            ),
+            default_fields: sorted_default_fields(default_fields),
+            nested_src: false,
+            span_ids: false,
+            level_mapper: Box::new(DefaultLevelMapper),
+            level_name: false,
+            timestamper: Timestamper::new(TimestampFormat::default())
+                .expect("The default timestamp format does not require the local offset"),
+            clock: Box::new(SystemClock),
+            span_records: SpanRecords::default(),
+            enter_exit: EnterExitLimiter::new(EnterExitRecords::None),
+            span_record_filter: None,
+            span_records_own_fields_only: false,
+            live_inherited_fields: false,
+            error_backtraces: false,
+            message_formatter: Box::new(DefaultMessageFormatter),
+            field_collision_policy: FieldCollisionPolicy::default(),
+            field_order: FieldOrder::default(),
+            diagnostics: Arc::default(),
+            diagnostic_handler: None,
+            fallback_writer: None,
+            #[cfg(feature = "otel")]
+            otel_context: false,
         }
     }
 
+    /// Start configuring a `BunyanFormattingLayer` via a [`BunyanFormattingLayerBuilder`].
+    ///
+    /// `name` and `make_writer` have the same meaning they have in [`BunyanFormattingLayer::new`];
+    /// all other options fall back to their defaults unless they are explicitly set.
+    /// The configuration is validated when calling [`BunyanFormattingLayerBuilder::build`].
+    ///
+    /// ```rust
+    /// use serde_json::json;
+    /// use tracing_bunyan_formatter::BunyanFormattingLayer;
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+    ///     .hostname("my-host")
+    ///     .default_field("environment", json!("production"))
+    ///     .build()
+    ///     .expect("Invalid configuration");
+    /// ```
+    pub fn builder(name: String, make_writer: W) -> BunyanFormattingLayerBuilder<W> {
+        BunyanFormattingLayerBuilder::new(name, make_writer)
+    }
+
+    /// The counters of the issues this layer ran into (dropped fields, serialization errors,
+    /// write failures).
+    ///
+    /// Grab them before handing the layer over to a subscriber:
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
+    /// use tracing_subscriber::prelude::*;
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), std::io::stdout);
+    /// let diagnostics = formatting_layer.diagnostics();
+    /// let subscriber = tracing_subscriber::registry()
+    ///     .with(JsonStorageLayer::default())
+    ///     .with(formatting_layer);
+    ///
+    /// tracing::subscriber::with_default(subscriber, || {
+    ///     tracing::info!(time = "now", "Hello");
+    /// });
+    /// assert_eq!(diagnostics.dropped_fields(), 1);
+    /// ```
+    pub fn diagnostics(&self) -> Arc<Diagnostics> {
+        Arc::clone(&self.diagnostics)
+    }
+
     fn serialize_bunyan_core_fields(
         &self,
         map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
-        message: &str,
-        level: &Level,
+        message: &Message<'_>,
+        level: BunyanLevel,
     ) -> Result<(), std::io::Error> {
-        map_serializer.serialize_entry(BUNYAN_VERSION, &self.bunyan_version)?;
-        map_serializer.serialize_entry(NAME, &self.name)?;
-        map_serializer.serialize_entry(MESSAGE, &message)?;
-        map_serializer.serialize_entry(LEVEL, &to_bunyan_level(level))?;
-        map_serializer.serialize_entry(HOSTNAME, &self.hostname)?;
-        map_serializer.serialize_entry(PID, &self.pid)?;
-        if let Ok(time) = &time::OffsetDateTime::now_utc().format(&Rfc3339) {
-            map_serializer.serialize_entry(TIME, time)?;
+        map_serializer.serialize_entry(MESSAGE, message)?;
+        map_serializer.serialize_entry(LEVEL, &level.as_u16())?;
+        if self.level_name {
+            map_serializer.serialize_entry(LEVEL_NAME, level.as_str())?;
         }
+        map_serializer.serialize_entry(TIME, &self.timestamper.timestamp(self.clock.now()))?;
         Ok(())
     }
 
-    /// Given a span, it serialised it to a in-memory buffer (vector of bytes).
-    fn serialize_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+    /// Serialize the location in the source code a record originates from.
+    fn serialize_source_location(
         &self,
-        span: &SpanRef<S>,
-        ty: Type,
-    ) -> Result<Vec<u8>, std::io::Error> {
-        let mut buffer = Vec::new();
-        let mut serializer = serde_json::Serializer::new(&mut buffer);
-        let mut map_serializer = serializer.serialize_map(None)?;
-        let message = format_span_context(span, ty);
-        self.serialize_bunyan_core_fields(&mut map_serializer, &message, span.metadata().level())?;
-        // Additional metadata useful for debugging
-        // They should be nested under `src` (see https://github.com/trentm/node-bunyan#src )
-        // but `tracing` does not support nested values yet
-        map_serializer.serialize_entry("target", span.metadata().target())?;
-        map_serializer.serialize_entry("line", &span.metadata().line())?;
-        map_serializer.serialize_entry("file", &span.metadata().file())?;
-
-        // Add all default fields
-        for (key, value) in self.default_fields.iter() {
-            if !BUNYAN_RESERVED_FIELDS.contains(&key.as_str()) {
-                map_serializer.serialize_entry(key, value)?;
-            } else {
-                tracing::debug!(
-                        "{} is a reserved field in the bunyan log format. Skipping it.",
-                        key
-                    );
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        metadata: &Metadata<'_>,
+    ) -> Result<(), std::io::Error> {
+        // Bunyan has no place for the target: it is kept as a top-level field either way.
+        map_serializer.serialize_entry("target", metadata.target())?;
+        if self.nested_src {
+            // See https://github.com/trentm/node-bunyan#src
+            let mut src = serde_json::Map::new();
+            if let Some(file) = metadata.file() {
+                src.insert("file".into(), file.into());
+            }
+            if let Some(line) = metadata.line() {
+                src.insert("line".into(), line.into());
             }
+            let func = metadata.module_path().unwrap_or_else(|| metadata.target());
+            src.insert("func".into(), func.into());
+            map_serializer.serialize_entry(SOURCE, &src)?;
+        } else {
+            // Additional metadata useful for debugging
+            // They should be nested under `src` (see https://github.com/trentm/node-bunyan#src )
+            // but we keep them flat unless `nested_src` has been enabled.
+            map_serializer.serialize_entry("line", &metadata.line())?;
+            map_serializer.serialize_entry("file", &metadata.file())?;
         }
+        Ok(())
+    }
 
-        let extensions = span.extensions();
-        if let Some(visitor) = extensions.get::<JsonStorage>() {
-            for (key, value) in visitor.values() {
-                if !BUNYAN_RESERVED_FIELDS.contains(key) {
-                    map_serializer.serialize_entry(key, value)?;
-                } else {
-                    tracing::debug!(
-                        "{} is a reserved field in the bunyan log format. Skipping it.",
-                        key
-                    );
+    /// Serialize the ids of `span` and of its parent span, as well as the id of the root span
+    /// of its span tree as `trace_id`, if `span_ids` has been enabled.
+    fn serialize_span_ids<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        span: &SpanRef<S>,
+        extensions: &Extensions<'_>,
+    ) -> Result<(), std::io::Error> {
+        if !self.span_ids {
+            return Ok(());
+        }
+        map_serializer.serialize_entry(SPAN_ID, &span.id().into_u64())?;
+        if let Some(parent) = span.parent() {
+            map_serializer.serialize_entry(PARENT_SPAN_ID, &parent.id().into_u64())?;
+        }
+        if let Some(trace_id) = extensions
+            .get::<JsonStorage>()
+            .and_then(JsonStorage::trace_id)
+        {
+            map_serializer.serialize_entry(TRACE_ID, &trace_id.into_u64())?;
+        }
+        Ok(())
+    }
+
+    /// Serialize the W3C trace id and span id of the OpenTelemetry context attached to `span`
+    /// by `tracing-opentelemetry`, if `otel_context` has been enabled.
+    #[cfg(feature = "otel")]
+    fn serialize_otel_context(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        extensions: &Extensions<'_>,
+    ) -> Result<(), std::io::Error> {
+        if !self.otel_context {
+            return Ok(());
+        }
+        // The OpenTelemetry context is built lazily, the first time the span is entered:
+        // the ids are missing from the START record of spans that are not entered right away.
+        if let Some(ids) = OtelIds::from_extensions(extensions) {
+            map_serializer.serialize_entry(TRACE_ID, &ids.trace_id.to_string())?;
+            map_serializer.serialize_entry(SPAN_ID, &ids.span_id.to_string())?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(feature = "otel"))]
+    fn serialize_otel_context(
+        &self,
+        _map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        _extensions: &Extensions<'_>,
+    ) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    /// Fields we populate ourselves cannot be overwritten by default fields, span fields or
+    /// event fields.
+    /// `src` and the span ids are only reserved when the corresponding options are enabled.
+    fn is_reserved_field(&self, key: &str) -> bool {
+        BUNYAN_RESERVED_FIELDS.contains(&key)
+            || (self.nested_src && key == SOURCE)
+            || (self.span_ids && [SPAN_ID, PARENT_SPAN_ID, TRACE_ID].contains(&key))
+            || (self.otel_context_enabled() && [SPAN_ID, TRACE_ID].contains(&key))
+            || (self.level_name && key == LEVEL_NAME)
+            || matches!(&self.field_collision_policy, FieldCollisionPolicy::Nest(nest_key) if nest_key == key)
+    }
+
+    fn otel_context_enabled(&self) -> bool {
+        #[cfg(feature = "otel")]
+        return self.otel_context;
+        #[cfg(not(feature = "otel"))]
+        return false;
+    }
+
+    /// Serialize the fields provided by the user (default fields, span fields and event fields),
+    /// sorting them if required by `field_order`.
+    fn serialize_user_fields<'a>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        fields: impl Iterator<Item = (&'a str, &'a Value)>,
+    ) -> Result<(), std::io::Error> {
+        if self.field_order == FieldOrder::Alphabetical {
+            let mut fields: Vec<_> = fields.collect();
+            // A stable sort keeps the relative order of duplicated keys.
+            fields.sort_by_key(|(key, _)| *key);
+            self.serialize_ordered_user_fields(map_serializer, fields.into_iter())
+        } else {
+            self.serialize_ordered_user_fields(map_serializer, fields)
+        }
+    }
+
+    /// Serialize the fields provided by the user in the order they are given, applying
+    /// `field_collision_policy` to the ones clashing with the fields we populate ourselves.
+    fn serialize_ordered_user_fields<'a>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        fields: impl Iterator<Item = (&'a str, &'a Value)>,
+    ) -> Result<(), std::io::Error> {
+        match &self.field_collision_policy {
+            FieldCollisionPolicy::Nest(nest_key) => {
+                map_serializer.serialize_entry(nest_key, &NestedFields(fields.collect()))?;
+            }
+            policy => {
+                for (key, value) in fields {
+                    if !self.is_reserved_field(key) {
+                        map_serializer.serialize_entry(key, value)?;
+                    } else if let FieldCollisionPolicy::Prefix(prefix) = policy {
+                        map_serializer.serialize_entry(&format!("{}{}", prefix, key), value)?;
+                    } else {
+                        self.report(Diagnostic::ReservedFieldDropped { key });
+                    }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Get the fields of `span` out of its `extensions`, resolving the inherited ones from its
+    /// ancestors if `live_inherited_fields` is enabled.
+    ///
+    /// The extensions of a span are behind a `RwLock` which lets waiting writers go first:
+    /// locking them a second time while holding them deadlocks if another thread records a
+    /// field of the span in the meantime. They are locked once per record and passed around.
+    fn span_fields<'e, S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        span: &SpanRef<S>,
+        extensions: &'e Extensions<'_>,
+    ) -> Option<Cow<'e, JsonStorage<'static>>> {
+        let fields = extensions.get::<JsonStorage>()?;
+        if self.live_inherited_fields {
+            Some(Cow::Owned(fields.resolve_live(span)))
+        } else {
+            Some(Cow::Borrowed(fields))
+        }
+    }
+
+    /// Check both `span_records` (or `enter_exit` for ENTER and EXIT records) and
+    /// `span_record_filter` to decide if a span record should be emitted for `span`.
+    fn should_emit_span_record<
+        S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+    >(
+        &self,
+        span: &SpanRef<S>,
+        ty: &Type,
+    ) -> bool {
+        let allowed = match ty {
+            Type::Enter | Type::Exit => self.enter_exit.is_enabled(),
+            _ => self.span_records.allows(ty),
+        };
+        if !allowed {
+            return false;
+        }
+        match &self.span_record_filter {
+            Some(filter) => match self.span_fields(span, &span.extensions()) {
+                Some(fields) => filter.should_emit(span.metadata(), &fields, ty),
+                None => filter.should_emit(span.metadata(), &JsonStorage::default(), ty),
+            },
+            None => true,
+        }
+    }
+
+    /// Serialize a record to `buffer`: the constant core fields, then the fields added by
+    /// `serialize_fields`.
+    fn serialize_record(
+        &self,
+        buffer: &mut Vec<u8>,
+        serialize_fields: impl FnOnce(&mut RecordSerializer<'_, '_>) -> Result<(), std::io::Error>,
+    ) -> Result<(), std::io::Error> {
+        // The constant fields leave the JSON object open...
+        buffer.extend_from_slice(&self.constant_fields);
+        let start = buffer.len();
+        let mut serializer = serde_json::Serializer::new(&mut *buffer);
+        let mut map_serializer = serializer.serialize_map(None)?;
+        serialize_fields(&mut map_serializer)?;
         map_serializer.end()?;
-        Ok(buffer)
+        // ...and the other fields are serialized as a map of their own (never empty, `msg` is
+        // always there): its opening brace becomes the comma following the constant fields.
+        buffer[start] = b',';
+        Ok(())
+    }
+
+    /// Given a span, it serialised it to a in-memory buffer (vector of bytes).
+    fn serialize_span<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        &self,
+        map_serializer: &mut impl SerializeMap<Error = serde_json::Error>,
+        span: &SpanRef<S>,
+        ty: Type,
+    ) -> Result<(), std::io::Error> {
+        let message = Message {
+            formatter: self.message_formatter.as_ref(),
+            span: Some(span.metadata()),
+            ty: &ty,
+            message: None,
+        };
+        let extensions = span.extensions();
+        let visitor = self.span_fields(span, &extensions);
+        let level = match &visitor {
+            Some(fields) => self.level_mapper.map_level(span.metadata(), fields),
+            None => self
+                .level_mapper
+                .map_level(span.metadata(), &JsonStorage::default()),
+        };
+        self.serialize_bunyan_core_fields(map_serializer, &message, level)?;
+        self.serialize_source_location(map_serializer, span.metadata())?;
+        self.serialize_span_ids(map_serializer, span, &extensions)?;
+        self.serialize_otel_context(map_serializer, &extensions)?;
+
+        // Add all default fields and the fields of the span
+        let default_fields = self
+            .default_fields
+            .iter()
+            .map(|(key, value)| (key.as_str(), value));
+        if self.span_records_own_fields_only {
+            let span_fields = visitor.iter().flat_map(|visitor| visitor.own_fields());
+            self.serialize_user_fields(map_serializer, default_fields.chain(span_fields))
+        } else {
+            let span_fields = visitor.iter().flat_map(|visitor| visitor.iter());
+            self.serialize_user_fields(map_serializer, default_fields.chain(span_fields))
+        }
     }
 
     /// Given an in-memory buffer holding a complete serialised record, flush it to the writer
@@ -158,18 +510,528 @@ impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayer<W> {
     /// If we write directly to the writer returned by self.make_writer in more than one go
     /// we can end up with broken/incoherent bits and pieces of those records when
     /// running multi-threaded/concurrent programs.
-    fn emit(&self, mut buffer: Vec<u8>) -> Result<(), std::io::Error> {
-        buffer.write_all(b"\n")?;
-        self.make_writer.make_writer().write_all(&buffer)
+    ///
+    /// If the write fails, the record is written to the fallback writer, if there is one.
+    /// Failures are reported as [`Diagnostic`]s.
+    fn emit(&self, buffer: &mut Vec<u8>) {
+        buffer.push(b'\n');
+        let error = match self.make_writer.make_writer().write_all(buffer) {
+            Ok(()) => return,
+            Err(e) => e,
+        };
+        self.report(Diagnostic::WriteFailed(&error));
+        match &self.fallback_writer {
+            Some(fallback_writer) => {
+                if let Err(e) = fallback_writer.write_record(buffer) {
+                    self.report(Diagnostic::FallbackWriteFailed(&e));
+                    self.report(Diagnostic::RecordDropped);
+                }
+            }
+            None => self.report(Diagnostic::RecordDropped),
+        }
+    }
+
+    /// Serialize a record (see [`BunyanFormattingLayer::serialize_record`]) in a reusable buffer
+    /// and emit it, reporting any failure along the way.
+    fn emit_record(
+        &self,
+        serialize_fields: impl FnOnce(&mut RecordSerializer<'_, '_>) -> Result<(), std::io::Error>,
+    ) {
+        with_buffer(
+            |buffer| match self.serialize_record(buffer, serialize_fields) {
+                Ok(()) => self.emit(buffer),
+                Err(e) => {
+                    self.report(Diagnostic::SerializationFailed(&e));
+                    self.report(Diagnostic::RecordDropped);
+                }
+            },
+        )
     }
+
+    /// Count `diagnostic` and forward it to the diagnostic handler, if there is one.
+    ///
+    /// We must not use `tracing` here: it would re-enter the subscriber we are part of.
+    fn report(&self, diagnostic: Diagnostic<'_>) {
+        self.diagnostics.record(&diagnostic);
+        if let Some(handler) = &self.diagnostic_handler {
+            handler.handle(&diagnostic);
+        }
+    }
+}
+
+/// Collects the configuration options of a [`BunyanFormattingLayer`].
+///
+/// Use [`BunyanFormattingLayer::builder`] to get one.
+pub struct BunyanFormattingLayerBuilder<W: for<'a> MakeWriter<'a> + 'static> {
+    make_writer: W,
+    name: String,
+    pid: Option<u32>,
+    hostname: Option<String>,
+    default_fields: HashMap<String, Value>,
+    nested_src: bool,
+    span_ids: bool,
+    level_mapper: Box<dyn LevelMapper>,
+    level_name: bool,
+    timestamp_format: TimestampFormat,
+    clock: Box<dyn Clock>,
+    span_records: SpanRecords,
+    enter_exit_records: EnterExitRecords,
+    span_record_filter: Option<Box<dyn SpanRecordFilter>>,
+    span_records_own_fields_only: bool,
+    live_inherited_fields: bool,
+    error_backtraces: bool,
+    message_formatter: Box<dyn MessageFormatter>,
+    field_collision_policy: FieldCollisionPolicy,
+    field_order: FieldOrder,
+    diagnostic_handler: Option<Box<dyn DiagnosticHandler>>,
+    fallback_writer: Option<Box<dyn RecordWriter>>,
+    #[cfg(feature = "otel")]
+    otel_context: bool,
 }
 
-/// The type of record we are dealing with: entering a span, exiting a span, an event.
+impl<W: for<'a> MakeWriter<'a> + 'static> BunyanFormattingLayerBuilder<W> {
+    fn new(name: String, make_writer: W) -> Self {
+        Self {
+            make_writer,
+            name,
+            pid: None,
+            hostname: None,
+            default_fields: HashMap::new(),
+            nested_src: false,
+            span_ids: false,
+            level_mapper: Box::new(DefaultLevelMapper),
+            level_name: false,
+            timestamp_format: TimestampFormat::default(),
+            clock: Box::new(SystemClock),
+            span_records: SpanRecords::default(),
+            enter_exit_records: EnterExitRecords::default(),
+            span_record_filter: None,
+            span_records_own_fields_only: false,
+            live_inherited_fields: false,
+            error_backtraces: false,
+            message_formatter: Box::new(DefaultMessageFormatter),
+            field_collision_policy: FieldCollisionPolicy::default(),
+            field_order: FieldOrder::default(),
+            diagnostic_handler: None,
+            fallback_writer: None,
+            #[cfg(feature = "otel")]
+            otel_context: false,
+        }
+    }
+
+    /// Override the `hostname` attached to all records.
+    ///
+    /// Defaults to the hostname of the machine the process is running on.
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    /// Override the `pid` attached to all records.
+    ///
+    /// Defaults to the id of the current process.
+    pub fn pid(mut self, pid: u32) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Attach a field to all records.
+    pub fn default_field(mut self, key: impl Into<String>, value: Value) -> Self {
+        self.default_fields.insert(key.into(), value);
+        self
+    }
+
+    /// Attach a set of fields to all records.
+    ///
+    /// They are merged with the fields already registered via [`default_field`](Self::default_field).
+    pub fn default_fields(mut self, default_fields: HashMap<String, Value>) -> Self {
+        self.default_fields.extend(default_fields);
+        self
+    }
+
+    /// Emit the source location of each record as a nested `src` object
+    /// (`{"file": ..., "line": ..., "func": ...}`), as expected by the
+    /// [Bunyan format](https://github.com/trentm/node-bunyan#src).
+    ///
+    /// `func` is the module path of the span or event, falling back to its target.
+    ///
+    /// By default this is disabled and `line` and `file` are emitted as top-level fields.
+    /// `target` is emitted as a top-level field either way.
+    pub fn nested_src(mut self, enabled: bool) -> Self {
+        self.nested_src = enabled;
+        self
+    }
+
+    /// Attach the id of the current span (`span_id`), of its parent (`parent_span_id`) and of
+    /// the root of its span tree (`trace_id`) to every record, to correlate the records
+    /// emitted by concurrent operations.
+    ///
+    /// The ids are the ones assigned by the subscriber: they are unique among spans that are
+    /// alive at the same time, but they can be reused once a span has been closed.
+    /// `trace_id` requires `JsonStorageLayer` to be registered before this layer.
+    ///
+    /// Disabled by default.
+    pub fn span_ids(mut self, enabled: bool) -> Self {
+        self.span_ids = enabled;
+        self
+    }
+
+    /// Customise how the Bunyan level of each record is determined, e.g. to emit records
+    /// at Bunyan's `fatal` level (60). See [`LevelMapper`] for an example.
+    ///
+    /// By default each `tracing` level is mapped to the Bunyan level with the same name.
+    pub fn level_mapper(mut self, level_mapper: impl LevelMapper) -> Self {
+        self.level_mapper = Box::new(level_mapper);
+        self
+    }
+
+    /// Emit the name of the level (e.g. `"info"`) as `level_name`, next to the numeric `level`
+    /// mandated by the Bunyan format, for tools that expect textual levels.
+    ///
+    /// Disabled by default.
+    pub fn level_name(mut self, enabled: bool) -> Self {
+        self.level_name = enabled;
+        self
+    }
+
+    /// Customise the format of the `time` field: the number of sub-second digits,
+    /// the offset from UTC or the use of Unix epoch timestamps.
+    ///
+    /// ```rust
+    /// use tracing_bunyan_formatter::{
+    ///     BunyanFormattingLayer, SubsecondPrecision, TimestampFormat, TimestampOffset,
+    /// };
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+    ///     .timestamp_format(TimestampFormat::Rfc3339 {
+    ///         precision: SubsecondPrecision::Millis,
+    ///         offset: TimestampOffset::Utc,
+    ///     })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    ///
+    /// Defaults to RFC 3339 timestamps in UTC with as many sub-second digits as needed.
+    pub fn timestamp_format(mut self, timestamp_format: TimestampFormat) -> Self {
+        self.timestamp_format = timestamp_format;
+        self
+    }
+
+    /// Get the `time` of records from `clock` instead of the system clock.
+    ///
+    /// See [`Clock`] for more details.
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Choose which span records (START and END) are emitted.
+    ///
+    /// Defaults to [`SpanRecords::All`].
+    pub fn span_records(mut self, span_records: SpanRecords) -> Self {
+        self.span_records = span_records;
+        self
+    }
+
+    /// Emit a record every time a span is entered (ENTER) and exited (EXIT).
+    ///
+    /// Defaults to [`EnterExitRecords::None`].
+    /// ```rust
+    /// use tracing_bunyan_formatter::{BunyanFormattingLayer, EnterExitRecords};
+    ///
+    /// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+    ///     .enter_exit_records(EnterExitRecords::RateLimited { max_per_second: 100 })
+    ///     .build()
+    ///     .unwrap();
+    /// ```
+    pub fn enter_exit_records(mut self, enter_exit_records: EnterExitRecords) -> Self {
+        self.enter_exit_records = enter_exit_records;
+        self
+    }
+
+    /// Decide, span by span, whether span records should be emitted.
+    /// See [`SpanRecordFilter`] for an example.
+    ///
+    /// The filter is only consulted for the record types allowed by
+    /// [`span_records`](Self::span_records).
+    pub fn span_record_filter(mut self, filter: impl SpanRecordFilter) -> Self {
+        self.span_record_filter = Some(Box::new(filter));
+        self
+    }
+
+    /// Only attach the fields recorded on the span itself to its START and END records,
+    /// leaving out the ones it inherits from its ancestors.
+    ///
+    /// Events keep carrying the fields of the current span and of its ancestors.
+    /// Disabled by default.
+    pub fn span_records_own_fields_only(mut self, enabled: bool) -> Self {
+        self.span_records_own_fields_only = enabled;
+        self
+    }
+
+    /// Resolve the fields a span inherits from its ancestors when formatting a record, instead
+    /// of using the values they had when the span was created.
+    ///
+    /// Fields recorded on a span via `Span::record` after the creation of its children then show
+    /// up on the records of its children, e.g. a `user_id` only known once the request has been
+    /// authenticated.
+    /// It comes at the cost of looking up the ancestors of the span for every record.
+    /// See [`JsonStorage::resolve_live`] for more details.
+    ///
+    /// Disabled by default.
+    pub fn live_inherited_fields(mut self, enabled: bool) -> Self {
+        self.live_inherited_fields = enabled;
+        self
+    }
+
+    /// Append a backtrace to the `stack` of the errors recorded as event fields, if enabled via
+    /// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+    ///
+    /// The backtrace is captured when the event is formatted: it shows where the error was
+    /// logged, not where it was created. Capturing and symbolizing a backtrace is expensive.
+    ///
+    /// Disabled by default. See [`JsonStorageLayer::with_error_backtraces`](crate::JsonStorageLayer::with_error_backtraces)
+    /// for span fields.
+    pub fn error_backtraces(mut self, enabled: bool) -> Self {
+        self.error_backtraces = enabled;
+        self
+    }
+
+    /// Customise the `msg` field of records. See [`MessageFormatter`] for an example.
+    ///
+    /// By default span records get `[SPAN_NAME - START]`/`[SPAN_NAME - END]` as message and
+    /// event messages are prefixed with `[SPAN_NAME - EVENT]` if they happen within a span.
+    pub fn message_formatter(mut self, message_formatter: impl MessageFormatter) -> Self {
+        self.message_formatter = Box::new(message_formatter);
+        self
+    }
+
+    /// Choose what happens to fields whose keys clash with the fields populated by this layer
+    /// (e.g. a span field named `name` or `time`).
+    ///
+    /// Defaults to [`FieldCollisionPolicy::Drop`].
+    pub fn field_collision_policy(mut self, policy: FieldCollisionPolicy) -> Self {
+        self.field_collision_policy = policy;
+        self
+    }
+
+    /// Choose the order in which the fields provided by the user (default fields, event fields
+    /// and span fields) are emitted.
+    ///
+    /// Defaults to [`FieldOrder::EventFieldsFirst`].
+    pub fn field_order(mut self, field_order: FieldOrder) -> Self {
+        self.field_order = field_order;
+        self
+    }
+
+    /// Get notified of the [`Diagnostic`]s reported by the layer, e.g. by writing them to
+    /// stderr with a [`DiagnosticWriter`](crate::DiagnosticWriter).
+    ///
+    /// They are always counted, see [`BunyanFormattingLayer::diagnostics`].
+    pub fn diagnostic_handler(mut self, handler: impl DiagnosticHandler) -> Self {
+        self.diagnostic_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Write records to `fallback_writer` (e.g. `std::io::stderr`) when writing them to the
+    /// main writer fails, instead of losing them.
+    ///
+    /// Write failures are reported as [`Diagnostic`]s whether there is a fallback writer or not:
+    /// use [`diagnostic_handler`](Self::diagnostic_handler) to react to them and
+    /// [`BunyanFormattingLayer::diagnostics`] to count the records that have been dropped.
+    pub fn fallback_writer<F>(mut self, fallback_writer: F) -> Self
+    where
+        F: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+    {
+        self.fallback_writer = Some(Box::new(fallback_writer));
+        self
+    }
+
+    /// Attach the W3C `trace_id` and `span_id` (as lowercase hex strings) of the OpenTelemetry
+    /// context of the current span to every record, so that logs can be joined with traces.
+    ///
+    /// The context is read from the span extensions populated by
+    /// [`tracing-opentelemetry`](https://docs.rs/tracing-opentelemetry)'s `OpenTelemetryLayer`,
+    /// which must be registered before this layer.
+    /// It cannot be combined with [`span_ids`](Self::span_ids), which uses the same keys.
+    ///
+    /// Disabled by default. Requires the `otel` feature.
+    #[cfg(feature = "otel")]
+    pub fn otel_context(mut self, enabled: bool) -> Self {
+        self.otel_context = enabled;
+        self
+    }
+
+    /// Validate the configuration and build a [`BunyanFormattingLayer`].
+    pub fn build(self) -> Result<BunyanFormattingLayer<W>, BuildError> {
+        if self.name.is_empty() {
+            return Err(BuildError::EmptyName);
+        }
+        if let Some(hostname) = &self.hostname {
+            if hostname.is_empty() {
+                return Err(BuildError::EmptyHostname);
+            }
+        }
+        match &self.field_collision_policy {
+            FieldCollisionPolicy::Prefix(prefix) | FieldCollisionPolicy::Nest(prefix)
+                if prefix.is_empty() =>
+            {
+                return Err(BuildError::EmptyFieldCollisionKey);
+            }
+            _ => {}
+        }
+        #[cfg(feature = "otel")]
+        if self.span_ids && self.otel_context {
+            return Err(BuildError::ConflictingOptions("span_ids", "otel_context"));
+        }
+
+        let layer = BunyanFormattingLayer {
+            make_writer: self.make_writer,
+            constant_fields: serialize_constant_fields(
+                &self.name,
+                &self
+                    .hostname
+                    .unwrap_or_else(|| gethostname::gethostname().to_string_lossy().into_owned()),
+                self.pid.unwrap_or_else(std::process::id),
+            ),
+            default_fields: sorted_default_fields(self.default_fields),
+            nested_src: self.nested_src,
+            span_ids: self.span_ids,
+            level_mapper: self.level_mapper,
+            level_name: self.level_name,
+            timestamper: Timestamper::new(self.timestamp_format)
+                .ok_or(BuildError::IndeterminateLocalOffset)?,
+            clock: self.clock,
+            span_records: self.span_records,
+            enter_exit: EnterExitLimiter::new(self.enter_exit_records),
+            span_record_filter: self.span_record_filter,
+            span_records_own_fields_only: self.span_records_own_fields_only,
+            live_inherited_fields: self.live_inherited_fields,
+            error_backtraces: self.error_backtraces,
+            message_formatter: self.message_formatter,
+            field_collision_policy: self.field_collision_policy,
+            field_order: self.field_order,
+            diagnostics: Arc::default(),
+            diagnostic_handler: self.diagnostic_handler,
+            fallback_writer: self.fallback_writer,
+            #[cfg(feature = "otel")]
+            otel_context: self.otel_context,
+        };
+        if let Some((key, _)) = layer
+            .default_fields
+            .iter()
+            .find(|(key, _)| layer.is_reserved_field(key))
+        {
+            return Err(BuildError::ReservedField(key.to_owned()));
+        }
+        Ok(layer)
+    }
+}
+
+/// The reasons why [`BunyanFormattingLayerBuilder::build`] can reject a configuration.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum BuildError {
+    /// Bunyan requires every record to carry a non-empty `name`.
+    EmptyName,
+    /// The `hostname` override is an empty string.
+    EmptyHostname,
+    /// A default field uses the key of one of the Bunyan core fields.
+    ReservedField(String),
+    /// The prefix or the nesting key of the field collision policy is an empty string.
+    EmptyFieldCollisionKey,
+    /// The timestamp format requires the local offset from UTC, but it could not be determined.
+    ///
+    /// On Unix this happens if the process is multi-threaded when the layer is built.
+    IndeterminateLocalOffset,
+    /// Two options that cannot be enabled at the same time have both been enabled.
+    ConflictingOptions(&'static str, &'static str),
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::EmptyName => write!(f, "the name of the logger cannot be empty"),
+            BuildError::EmptyHostname => write!(f, "the hostname cannot be empty"),
+            BuildError::ReservedField(key) => write!(
+                f,
+                "{} is a reserved field in the bunyan log format and cannot be used as a default field",
+                key
+            ),
+            BuildError::EmptyFieldCollisionKey => write!(
+                f,
+                "the prefix or nesting key of the field collision policy cannot be empty"
+            ),
+            BuildError::IndeterminateLocalOffset => {
+                write!(f, "the local offset from UTC could not be determined")
+            }
+            BuildError::ConflictingOptions(first, second) => {
+                write!(f, "{} and {} cannot be enabled at the same time", first, second)
+            }
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+/// What to do with user fields (default fields, span fields and event fields) whose keys clash
+/// with the fields populated by [`BunyanFormattingLayer`] (the Bunyan core fields, `src`, ...).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum FieldCollisionPolicy {
+    /// Skip the clashing fields.
+    #[default]
+    Drop,
+    /// Prepend the given prefix to the keys of the clashing fields,
+    /// e.g. `name` becomes `fields.name` with `Prefix("fields.".into())`.
+    Prefix(String),
+    /// Nest all user fields, clashing or not, in an object under the given key.
+    Nest(String),
+}
+
+/// The order in which the fields provided by the user are emitted.
+///
+/// Whatever the order, it is the same for all records and across runs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FieldOrder {
+    /// Default fields (sorted by key), then the fields of the event, then the fields of the
+    /// current span (see [`JsonStorage::iter`]), each in declaration order.
+    #[default]
+    EventFieldsFirst,
+    /// Like [`FieldOrder::EventFieldsFirst`], with the fields of the current span before
+    /// the fields of the event.
+    SpanFieldsFirst,
+    /// All fields sorted by key.
+    Alphabetical,
+}
+
+/// Sort default fields by key.
+fn sorted_default_fields(default_fields: HashMap<String, Value>) -> Vec<(String, Value)> {
+    let mut default_fields: Vec<_> = default_fields.into_iter().collect();
+    default_fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+    default_fields
+}
+
+/// Serialize a set of fields as a JSON object.
+struct NestedFields<'a>(Vec<(&'a str, &'a Value)>);
+
+impl Serialize for NestedFields<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_map(self.0.iter().copied())
+    }
+}
+
+/// The type of record we are dealing with: the creation of a span (START), its closing (END),
+/// an event, entering a span (ENTER) and exiting it (EXIT).
+///
+/// ENTER and EXIT records are only emitted if enabled via
+/// [`BunyanFormattingLayerBuilder::enter_exit_records`].
 #[derive(Clone, Debug)]
 pub enum Type {
     EnterSpan,
     ExitSpan,
     Event,
+    Enter,
+    Exit,
 }
 
 impl fmt::Display for Type {
@@ -178,49 +1040,22 @@ impl fmt::Display for Type {
             Type::EnterSpan => "START",
             Type::ExitSpan => "END",
             Type::Event => "EVENT",
+            Type::Enter => "ENTER",
+            Type::Exit => "EXIT",
         };
         write!(f, "{}", repr)
     }
 }
 
-/// Ensure consistent formatting of the span context.
-///
-/// Example: "[AN_INTERESTING_SPAN - START]"
-fn format_span_context<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
-    span: &SpanRef<S>,
-    ty: Type,
-) -> String {
-    format!("[{} - {}]", span.metadata().name().to_uppercase(), ty)
-}
-
-/// Ensure consistent formatting of event message.
-///
-/// Examples:
-/// - "[AN_INTERESTING_SPAN - EVENT] My event message" (for an event with a parent span)
-/// - "My event message" (for an event without a parent span)
-fn format_event_message<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
-    current_span: &Option<SpanRef<S>>,
-    event: &Event,
-    event_visitor: &JsonStorage<'_>,
-) -> String {
-    // Extract the "message" field, if provided. Fallback to the target, if missing.
-    let mut message = event_visitor
-        .values()
+/// Extract the "message" field of an event, if provided. Fallback to the target, if missing.
+fn event_message<'a>(event: &'a Event, event_visitor: &'a JsonStorage<'_>) -> &'a str {
+    event_visitor
         .get("message")
-        .map(|v| match v {
+        .and_then(|v| match v {
             Value::String(s) => Some(s.as_str()),
             _ => None,
         })
-        .flatten()
         .unwrap_or_else(|| event.metadata().target())
-        .to_owned();
-
-    // If the event is in the context of a span, prepend the span name to the message.
-    if let Some(span) = &current_span {
-        message = format!("{} {}", format_span_context(span, Type::Event), message);
-    }
-
-    message
 }
 
 impl<S, W> Layer<S> for BunyanFormattingLayer<W>
@@ -234,82 +1069,127 @@ where
         let current_span = ctx.lookup_current();
 
         let mut event_visitor = JsonStorage::default();
+        event_visitor.capture_error_backtraces(self.error_backtraces);
         event.record(&mut event_visitor);
 
-        // Opting for a closure to use the ? operator and get more linear code.
-        let format = || {
-            let mut buffer = Vec::new();
+        self.emit_record(|map_serializer| {
+            let message = Message {
+                formatter: self.message_formatter.as_ref(),
+                span: current_span.as_ref().map(|span| span.metadata()),
+                ty: &Type::Event,
+                message: Some(event_message(event, &event_visitor)),
+            };
+            let level = self
+                .level_mapper
+                .map_level(event.metadata(), &event_visitor);
+            self.serialize_bunyan_core_fields(map_serializer, &message, level)?;
+            self.serialize_source_location(map_serializer, event.metadata())?;
 
-            let mut serializer = serde_json::Serializer::new(&mut buffer);
-            let mut map_serializer = serializer.serialize_map(None)?;
-
-            let message = format_event_message(&current_span, event, &event_visitor);
-            self.serialize_bunyan_core_fields(
-                &mut map_serializer,
-                &message,
-                event.metadata().level(),
-            )?;
-            // Additional metadata useful for debugging
-            // They should be nested under `src` (see https://github.com/trentm/node-bunyan#src )
-            // but `tracing` does not support nested values yet
-            map_serializer.serialize_entry("target", event.metadata().target())?;
-            map_serializer.serialize_entry("line", &event.metadata().line())?;
-            map_serializer.serialize_entry("file", &event.metadata().file())?;
+            // Declared first as they must outlive the iterators borrowing from them.
+            let extensions = current_span.as_ref().map(|span| span.extensions());
+            let visitor = current_span
+                .as_ref()
+                .zip(extensions.as_ref())
+                .and_then(|(span, extensions)| self.span_fields(span, extensions));
+            if let Some((span, extensions)) = current_span.as_ref().zip(extensions.as_ref()) {
+                self.serialize_span_ids(map_serializer, span, extensions)?;
+                self.serialize_otel_context(map_serializer, extensions)?;
+            }
 
             // Add all default fields
-            for (key, value) in self.default_fields
+            let default_fields = self
+                .default_fields
                 .iter()
-                .filter(|(key, _)| key.as_str() != "message" && !BUNYAN_RESERVED_FIELDS.contains(&key.as_str()))
-            {
-                map_serializer.serialize_entry(key, value)?;
-            }
+                .map(|(key, value)| (key.as_str(), value))
+                .filter(|(key, _)| *key != "message");
 
             // Add all the other fields associated with the event, expect the message we already used.
-            for (key, value) in event_visitor
-                .values()
-                .iter()
-                .filter(|(&key, _)| key != "message" && !BUNYAN_RESERVED_FIELDS.contains(&key))
-            {
-                map_serializer.serialize_entry(key, value)?;
-            }
+            let event_fields = event_visitor.iter().filter(|(key, _)| *key != "message");
 
             // Add all the fields from the current span, if we have one.
-            if let Some(span) = &current_span {
-                let extensions = span.extensions();
-                if let Some(visitor) = extensions.get::<JsonStorage>() {
-                    for (key, value) in visitor.values() {
-                        if !BUNYAN_RESERVED_FIELDS.contains(key) {
-                            map_serializer.serialize_entry(key, value)?;
-                        } else {
-                            tracing::debug!(
-                                "{} is a reserved field in the bunyan log format. Skipping it.",
-                                key
-                            );
-                        }
-                    }
-                }
+            let span_fields = visitor.iter().flat_map(|visitor| visitor.iter());
+
+            match self.field_order {
+                FieldOrder::SpanFieldsFirst => self.serialize_user_fields(
+                    map_serializer,
+                    default_fields.chain(span_fields).chain(event_fields),
+                ),
+                FieldOrder::EventFieldsFirst | FieldOrder::Alphabetical => self
+                    .serialize_user_fields(
+                        map_serializer,
+                        default_fields.chain(event_fields).chain(span_fields),
+                    ),
             }
-            map_serializer.end()?;
-            Ok(buffer)
-        };
+        });
+    }
 
-        let result: std::io::Result<Vec<u8>> = format();
-        if let Ok(formatted) = result {
-            let _ = self.emit(formatted);
+    fn on_new_span(&self, _attrs: &Attributes, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        if !self.should_emit_span_record(&span, &Type::EnterSpan) {
+            return;
         }
+        self.emit_record(|map_serializer| {
+            self.serialize_span(map_serializer, &span, Type::EnterSpan)
+        });
     }
 
-    fn on_new_span(&self, _attrs: &Attributes, id: &Id, ctx: Context<'_, S>) {
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        #[cfg(feature = "otel")]
+        if self.otel_context {
+            let span = ctx.span(id).expect("Span not found, this is a bug");
+            OtelIds::cache(&span);
+        }
+        if !self.enter_exit.is_enabled() {
+            return;
+        }
+        let span = ctx.span(id).expect("Span not found, this is a bug");
+        // The filter goes first: filtered out spans do not count against the rate limit.
+        let emit = self.should_emit_span_record(&span, &Type::Enter)
+            && self.enter_exit.acquire(self.clock.instant());
+        if self.enter_exit.is_rate_limited() {
+            let mut extensions = span.extensions_mut();
+            match extensions.get_mut::<EmittedEnters>() {
+                Some(emitted) => emitted.push(emit),
+                None => {
+                    let mut emitted = EmittedEnters::default();
+                    emitted.push(emit);
+                    extensions.insert(emitted);
+                }
+            }
+        }
+        if emit {
+            self.emit_record(|map_serializer| {
+                self.serialize_span(map_serializer, &span, Type::Enter)
+            });
+        }
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        if !self.enter_exit.is_enabled() {
+            return;
+        }
         let span = ctx.span(id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::EnterSpan) {
-            let _ = self.emit(serialized);
+        // Skip the EXIT record if the matching ENTER record was skipped.
+        let entered = !self.enter_exit.is_rate_limited()
+            || span
+                .extensions_mut()
+                .get_mut::<EmittedEnters>()
+                .and_then(EmittedEnters::pop)
+                .unwrap_or(false);
+        if entered && self.should_emit_span_record(&span, &Type::Exit) {
+            self.emit_record(|map_serializer| {
+                self.serialize_span(map_serializer, &span, Type::Exit)
+            });
         }
     }
 
     fn on_close(&self, id: Id, ctx: Context<'_, S>) {
         let span = ctx.span(&id).expect("Span not found, this is a bug");
-        if let Ok(serialized) = self.serialize_span(&span, Type::ExitSpan) {
-            let _ = self.emit(serialized);
+        if !self.should_emit_span_record(&span, &Type::ExitSpan) {
+            return;
         }
+        self.emit_record(|map_serializer| {
+            self.serialize_span(map_serializer, &span, Type::ExitSpan)
+        });
     }
 }
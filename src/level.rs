@@ -0,0 +1,104 @@
+use crate::storage_layer::JsonStorage;
+use std::fmt;
+use tracing_core::metadata::{Level, Metadata};
+use tracing_log::AsLog;
+
+/// The log levels of the [Bunyan format](https://github.com/trentm/node-bunyan#levels).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BunyanLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+    /// Bunyan's `fatal` level has no `tracing` counterpart: it can only be produced
+    /// by a custom [`LevelMapper`].
+    Fatal,
+}
+
+impl BunyanLevel {
+    /// The numeric value of the level, as found in the `level` field of Bunyan records.
+    pub fn as_u16(&self) -> u16 {
+        match self {
+            BunyanLevel::Trace => 10,
+            BunyanLevel::Debug => 20,
+            BunyanLevel::Info => 30,
+            BunyanLevel::Warn => 40,
+            BunyanLevel::Error => 50,
+            BunyanLevel::Fatal => 60,
+        }
+    }
+
+    /// The name of the level, as understood by the `bunyan` CLI (e.g. `info`).
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BunyanLevel::Trace => "trace",
+            BunyanLevel::Debug => "debug",
+            BunyanLevel::Info => "info",
+            BunyanLevel::Warn => "warn",
+            BunyanLevel::Error => "error",
+            BunyanLevel::Fatal => "fatal",
+        }
+    }
+}
+
+/// Convert from log levels to Bunyan's levels.
+impl From<&Level> for BunyanLevel {
+    fn from(level: &Level) -> Self {
+        match level.as_log() {
+            log::Level::Error => BunyanLevel::Error,
+            log::Level::Warn => BunyanLevel::Warn,
+            log::Level::Info => BunyanLevel::Info,
+            log::Level::Debug => BunyanLevel::Debug,
+            log::Level::Trace => BunyanLevel::Trace,
+        }
+    }
+}
+
+impl fmt::Display for BunyanLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Decides the Bunyan level of each record, given the metadata of the span or event and the
+/// fields recorded on it.
+///
+/// It is implemented for all closures with a matching signature:
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, BunyanLevel, JsonStorage};
+/// use tracing::Metadata;
+///
+/// // Promote events with a `fatal = true` field to Bunyan's `fatal` level.
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .level_mapper(|metadata: &Metadata<'_>, fields: &JsonStorage<'_>| {
+///         if fields.get("fatal") == Some(&serde_json::Value::Bool(true)) {
+///             BunyanLevel::Fatal
+///         } else {
+///             BunyanLevel::from(metadata.level())
+///         }
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub trait LevelMapper: Send + Sync + 'static {
+    fn map_level(&self, metadata: &Metadata<'_>, fields: &JsonStorage<'_>) -> BunyanLevel;
+}
+
+impl<F> LevelMapper for F
+where
+    F: Fn(&Metadata<'_>, &JsonStorage<'_>) -> BunyanLevel + Send + Sync + 'static,
+{
+    fn map_level(&self, metadata: &Metadata<'_>, fields: &JsonStorage<'_>) -> BunyanLevel {
+        self(metadata, fields)
+    }
+}
+
+/// Map each `tracing` level to the Bunyan level with the same name.
+pub(crate) struct DefaultLevelMapper;
+
+impl LevelMapper for DefaultLevelMapper {
+    fn map_level(&self, metadata: &Metadata<'_>, _fields: &JsonStorage<'_>) -> BunyanLevel {
+        BunyanLevel::from(metadata.level())
+    }
+}
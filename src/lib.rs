@@ -6,10 +6,11 @@
 //! - [`JsonStorageLayer`], to attach contextual information to spans for ease of consumption by
 //!   downstream [`Layer`]s, via [`JsonStorage`] and [`Span`]'s [`extensions`](https://docs.rs/tracing-subscriber/0.2.5/tracing_subscriber/registry/struct.ExtensionsMut.html);
 //! - [`BunyanFormattingLayer`]`, which emits a [bunyan](https://github.com/trentm/node-bunyan)-compatible formatted record upon entering a span,
-//!  existing a span and event creation.
+//!   existing a span and event creation.
 //!
 //! **Important**: each span will inherit all fields and properties attached to its parent - this is
 //! currently not the behaviour provided by [`tracing_subscriber::fmt::Layer`](https://docs.rs/tracing-subscriber/0.2.5/tracing_subscriber/fmt/struct.Layer.html).
+//! You can narrow down which fields are inherited via [`JsonStorageLayer::with_inheritance`].
 //!
 //! ## Example
 //!
@@ -36,7 +37,7 @@
 //! fn main() {
 //!     let formatting_layer = BunyanFormattingLayer::new("tracing_demo".into(), std::io::stdout);
 //!     let subscriber = Registry::default()
-//!         .with(JsonStorageLayer)
+//!         .with(JsonStorageLayer::default())
 //!         .with(formatting_layer);
 //!     tracing::subscriber::set_global_default(subscriber).unwrap();
 //!
@@ -73,7 +74,7 @@
 //! in implementing your own formatter, for whatever reason or purpose.
 //!
 //! You can also add another enrichment layer following the [`JsonStorageLayer`] to collect
-//! additional information about each span and store it in [`JsonStorage`].
+//! additional information about each span and store it in [`JsonStorage`] (see [`JsonStorage::insert`]).
 //! We could have pursued this compositional approach to add `elapsed_milliseconds` to each span
 //! instead of baking it in [`JsonStorage`] itself.
 //!
@@ -81,6 +82,10 @@
 //!
 //! You can enable the `arbitrary_precision` feature to handle numbers of arbitrary size losslessly. Be aware of a [known issue with untagged deserialization](https://github.com/LukeMathWalker/tracing-bunyan-formatter/issues/4).
 //!
+//! You can enable the `otel` feature to attach the OpenTelemetry trace and span ids tracked by
+//! [`tracing-opentelemetry`](https://docs.rs/tracing-opentelemetry) to each record, see
+//! `BunyanFormattingLayerBuilder::otel_context`.
+//!
 //! [`Layer`]: https://docs.rs/tracing-subscriber/0.2.5/tracing_subscriber/layer/trait.Layer.html
 //! [`JsonStorageLayer`]: struct.JsonStorageLayer.html
 //! [`JsonStorage`]: struct.JsonStorage.html
@@ -89,8 +94,26 @@
 //! [`Subscriber`]: https://docs.rs/tracing-core/0.1.10/tracing_core/subscriber/trait.Subscriber.html
 //! [`tracing`]: https://docs.rs/tracing
 //! [`tracing`]: https://docs.rs/tracing-subscriber
+mod clock;
+mod diagnostics;
 mod formatting_layer;
+mod level;
+mod message;
+#[cfg(feature = "otel")]
+mod otel;
+mod span_records;
+mod span_timings;
 mod storage_layer;
+mod timestamp;
+mod writer;
 
+pub use clock::*;
+pub use diagnostics::*;
 pub use formatting_layer::*;
+pub use level::*;
+pub use message::*;
+pub use span_records::*;
+pub use span_timings::*;
 pub use storage_layer::*;
+pub use timestamp::*;
+pub use writer::*;
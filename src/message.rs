@@ -0,0 +1,128 @@
+use crate::formatting_layer::Type;
+use serde::ser::{Serialize, Serializer};
+use std::fmt::{self, Write};
+use tracing_core::metadata::Metadata;
+
+/// Builds the `msg` field of each record.
+///
+/// It receives:
+/// - the metadata of the span the record refers to, `None` for events emitted outside of any span;
+/// - the type of record;
+/// - the message of the event (falling back to its target if it has none), `None` for span records.
+///
+/// It is implemented for all closures with a matching signature:
+/// ```rust
+/// use tracing::Metadata;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, Type};
+///
+/// // Keep the original span names and do not prefix event messages.
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .message_formatter(|span: Option<&Metadata<'_>>, ty: &Type, message: Option<&str>| {
+///         match (span, message) {
+///             (_, Some(message)) => message.to_owned(),
+///             (Some(span), None) => format!("{} {}", ty, span.name()),
+///             (None, None) => ty.to_string(),
+///         }
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub trait MessageFormatter: Send + Sync + 'static {
+    fn format_message(
+        &self,
+        span: Option<&Metadata<'_>>,
+        ty: &Type,
+        message: Option<&str>,
+    ) -> String;
+
+    /// Write the message to `writer` instead of returning it.
+    ///
+    /// The layer always calls this method, which writes the message straight to the record
+    /// being serialized: override it to avoid allocating a `String` for each record.
+    fn write_message(
+        &self,
+        writer: &mut dyn Write,
+        span: Option<&Metadata<'_>>,
+        ty: &Type,
+        message: Option<&str>,
+    ) -> fmt::Result {
+        writer.write_str(&self.format_message(span, ty, message))
+    }
+}
+
+impl<F> MessageFormatter for F
+where
+    F: Fn(Option<&Metadata<'_>>, &Type, Option<&str>) -> String + Send + Sync + 'static,
+{
+    fn format_message(
+        &self,
+        span: Option<&Metadata<'_>>,
+        ty: &Type,
+        message: Option<&str>,
+    ) -> String {
+        self(span, ty, message)
+    }
+}
+
+/// Ensure consistent formatting of the span context and of event messages.
+///
+/// Examples:
+/// - "[AN_INTERESTING_SPAN - START]" (for a span record)
+/// - "[AN_INTERESTING_SPAN - EVENT] My event message" (for an event with a parent span)
+/// - "My event message" (for an event without a parent span)
+pub(crate) struct DefaultMessageFormatter;
+
+impl MessageFormatter for DefaultMessageFormatter {
+    fn format_message(
+        &self,
+        span: Option<&Metadata<'_>>,
+        ty: &Type,
+        message: Option<&str>,
+    ) -> String {
+        let mut formatted = String::new();
+        // Writing to a `String` cannot fail.
+        let _ = self.write_message(&mut formatted, span, ty, message);
+        formatted
+    }
+
+    fn write_message(
+        &self,
+        writer: &mut dyn Write,
+        span: Option<&Metadata<'_>>,
+        ty: &Type,
+        message: Option<&str>,
+    ) -> fmt::Result {
+        if let Some(span) = span {
+            writer.write_char('[')?;
+            for c in span.name().chars().flat_map(char::to_uppercase) {
+                writer.write_char(c)?;
+            }
+            write!(writer, " - {}]", ty)?;
+            if message.is_some() {
+                writer.write_char(' ')?;
+            }
+        }
+        writer.write_str(message.unwrap_or_default())
+    }
+}
+
+/// The `msg` field of a record, written straight to the serializer by a [`MessageFormatter`].
+pub(crate) struct Message<'a> {
+    pub(crate) formatter: &'a dyn MessageFormatter,
+    pub(crate) span: Option<&'a Metadata<'a>>,
+    pub(crate) ty: &'a Type,
+    pub(crate) message: Option<&'a str>,
+}
+
+impl fmt::Display for Message<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.formatter
+            .write_message(f, self.span, self.ty, self.message)
+    }
+}
+
+impl Serialize for Message<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
@@ -0,0 +1,50 @@
+use opentelemetry::{SpanId, TraceId};
+use tracing::Subscriber;
+use tracing_opentelemetry::OtelData;
+use tracing_subscriber::registry::{Extensions, SpanRef};
+
+/// The OpenTelemetry ids of a span.
+///
+/// `tracing-opentelemetry` removes `OtelData` from the span extensions when the span is closed,
+/// possibly before we get to format its END record: we keep our own copy of the ids, taken
+/// when the span is entered.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OtelIds {
+    pub(crate) trace_id: TraceId,
+    pub(crate) span_id: SpanId,
+}
+
+impl OtelIds {
+    /// Get the OpenTelemetry ids of a span from its extensions, if its context has been built
+    /// and it is valid.
+    pub(crate) fn from_extensions(extensions: &Extensions<'_>) -> Option<Self> {
+        let ids = match extensions.get::<OtelData>() {
+            Some(otel_data) => OtelIds {
+                trace_id: otel_data.trace_id()?,
+                span_id: otel_data.span_id()?,
+            },
+            None => *extensions.get::<OtelIds>()?,
+        };
+        if ids.trace_id == TraceId::INVALID || ids.span_id == SpanId::INVALID {
+            None
+        } else {
+            Some(ids)
+        }
+    }
+
+    /// Store a copy of the OpenTelemetry ids of `span` in its extensions, if we don't have one yet.
+    pub(crate) fn cache<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>>(
+        span: &SpanRef<S>,
+    ) {
+        let ids = {
+            let extensions = span.extensions();
+            if extensions.get::<OtelIds>().is_some() {
+                return;
+            }
+            Self::from_extensions(&extensions)
+        };
+        if let Some(ids) = ids {
+            span.extensions_mut().insert(ids);
+        }
+    }
+}
@@ -0,0 +1,159 @@
+use crate::formatting_layer::Type;
+use crate::storage_layer::JsonStorage;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::thread::{self, ThreadId};
+use std::time::{Duration, Instant};
+use tracing_core::metadata::Metadata;
+
+/// Which of the records marking the beginning (START) and the end (END) of spans
+/// are emitted by [`BunyanFormattingLayer`](crate::BunyanFormattingLayer).
+///
+/// Span fields are attached to the events emitted within the span regardless.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SpanRecords {
+    /// Both START and END records (the default).
+    #[default]
+    All,
+    /// END records only: they carry the duration of the span.
+    EndOnly,
+    /// No span records, only events.
+    None,
+}
+
+impl SpanRecords {
+    pub(crate) fn allows(&self, ty: &Type) -> bool {
+        match self {
+            SpanRecords::All => true,
+            SpanRecords::EndOnly => matches!(ty, Type::ExitSpan),
+            SpanRecords::None => false,
+        }
+    }
+}
+
+/// Whether [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) emits a record every time
+/// a span is entered (ENTER) and exited (EXIT).
+///
+/// The span of a future instrumented via `.instrument()` is entered every time the future is
+/// polled: these records tell when the task was actually running.
+/// They can be filtered span by span via a [`SpanRecordFilter`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum EnterExitRecords {
+    /// No ENTER or EXIT records (the default).
+    #[default]
+    None,
+    /// An ENTER and an EXIT record every time a span is entered and exited.
+    All,
+    /// At most `max_per_second` ENTER records per second, across all spans.
+    /// When an ENTER record is skipped, so is the matching EXIT record.
+    RateLimited { max_per_second: u32 },
+}
+
+/// Enforces the [`EnterExitRecords`] setting of a layer.
+#[derive(Debug)]
+pub(crate) struct EnterExitLimiter {
+    records: EnterExitRecords,
+    /// The start of the current one-second window and the number of ENTER records emitted
+    /// since then.
+    window: Mutex<Option<(Instant, u32)>>,
+}
+
+impl EnterExitLimiter {
+    pub(crate) fn new(records: EnterExitRecords) -> Self {
+        Self {
+            records,
+            window: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn is_enabled(&self) -> bool {
+        self.records != EnterExitRecords::None
+    }
+
+    /// Whether ENTER records can be skipped, in which case EXIT records must follow suit.
+    pub(crate) fn is_rate_limited(&self) -> bool {
+        matches!(self.records, EnterExitRecords::RateLimited { .. })
+    }
+
+    /// Take one of the ENTER records allowed in the current window, if there is any left.
+    pub(crate) fn acquire(&self, now: Instant) -> bool {
+        let max_per_second = match self.records {
+            EnterExitRecords::None => return false,
+            EnterExitRecords::All => return true,
+            EnterExitRecords::RateLimited { max_per_second } => max_per_second,
+        };
+        let mut window = self.window.lock().unwrap_or_else(|e| e.into_inner());
+        let count = match &mut *window {
+            Some((start, count))
+                if now.saturating_duration_since(*start) < Duration::from_secs(1) =>
+            {
+                count
+            }
+            // Start a new window.
+            window => &mut window.insert((now, 0)).1,
+        };
+        if *count < max_per_second {
+            *count += 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Whether the ENTER records of a span were emitted, innermost enter last: it is kept in the
+/// extensions of the span when ENTER records are rate limited, to skip the matching EXIT records.
+///
+/// A span can be entered on several threads at once: each thread has its own stack.
+#[derive(Debug, Default)]
+pub(crate) struct EmittedEnters(HashMap<ThreadId, Vec<bool>>);
+
+impl EmittedEnters {
+    /// Record whether the ENTER record of the span on the current thread was emitted.
+    pub(crate) fn push(&mut self, emitted: bool) {
+        self.0
+            .entry(thread::current().id())
+            .or_default()
+            .push(emitted);
+    }
+
+    /// Whether the ENTER record matching the exit of the span on the current thread was emitted.
+    pub(crate) fn pop(&mut self) -> Option<bool> {
+        let thread = thread::current().id();
+        let stack = self.0.get_mut(&thread)?;
+        let emitted = stack.pop();
+        if stack.is_empty() {
+            self.0.remove(&thread);
+        }
+        emitted
+    }
+}
+
+/// Decides, span by span, whether a START, END, ENTER or EXIT record should be emitted, given the metadata
+/// of the span, its fields and the type of record.
+///
+/// It is implemented for all closures with a matching signature:
+/// ```rust
+/// use tracing::Metadata;
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorage, Type};
+///
+/// // Skip span records for spans with a `log_span = false` field.
+/// let formatting_layer = BunyanFormattingLayer::builder("tracing_example".into(), std::io::stdout)
+///     .span_record_filter(|_metadata: &Metadata<'_>, fields: &JsonStorage<'_>, _ty: &Type| {
+///         fields.get("log_span") != Some(&serde_json::Value::Bool(false))
+///     })
+///     .build()
+///     .unwrap();
+/// ```
+pub trait SpanRecordFilter: Send + Sync + 'static {
+    fn should_emit(&self, metadata: &Metadata<'_>, fields: &JsonStorage<'_>, ty: &Type) -> bool;
+}
+
+impl<F> SpanRecordFilter for F
+where
+    F: Fn(&Metadata<'_>, &JsonStorage<'_>, &Type) -> bool + Send + Sync + 'static,
+{
+    fn should_emit(&self, metadata: &Metadata<'_>, fields: &JsonStorage<'_>, ty: &Type) -> bool {
+        self(metadata, fields, ty)
+    }
+}
@@ -0,0 +1,293 @@
+use crate::clock::Clock;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+/// The timing fields [`JsonStorageLayer`](crate::JsonStorageLayer) records on spans when they
+/// are closed, see [`JsonStorageLayer::with_span_timings`](crate::JsonStorageLayer::with_span_timings).
+///
+/// Only `elapsed_milliseconds` is recorded by default.
+/// The name and the unit of each duration can be customised via [`DurationField`]s.
+///
+/// A span can be entered and exited many times before being closed, e.g. the span of a future
+/// instrumented via `.instrument()` is entered every time the future is polled: `busy_ns` and
+/// `idle_ns` tell the time spent working from the time spent waiting.
+/// ```rust
+/// use tracing_bunyan_formatter::{DurationField, DurationUnit, JsonStorageLayer, SpanTimings};
+///
+/// let storage_layer = JsonStorageLayer::default().with_span_timings(
+///     SpanTimings::none()
+///         .busy_ns(true)
+///         .idle_ns(true)
+///         .enter_count(true),
+/// );
+///
+/// // The time the span took, in fractional seconds, under the `duration` key.
+/// let storage_layer = JsonStorageLayer::default().with_span_timings(
+///     SpanTimings::none().elapsed(DurationField::new("duration", DurationUnit::Seconds)),
+/// );
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SpanTimings {
+    elapsed: Option<DurationField>,
+    busy: Option<DurationField>,
+    idle: Option<DurationField>,
+    enter_count: Option<Cow<'static, str>>,
+    wall: Option<DurationField>,
+}
+
+impl Default for SpanTimings {
+    fn default() -> Self {
+        Self::none().elapsed_milliseconds(true)
+    }
+}
+
+impl SpanTimings {
+    /// No timing field.
+    pub fn none() -> Self {
+        Self {
+            elapsed: None,
+            busy: None,
+            idle: None,
+            enter_count: None,
+            wall: None,
+        }
+    }
+
+    /// All the timing fields, with their default names and units.
+    pub fn all() -> Self {
+        Self::none()
+            .elapsed_milliseconds(true)
+            .busy_ns(true)
+            .idle_ns(true)
+            .enter_count(true)
+            .wall_ns(true)
+    }
+
+    /// `elapsed_milliseconds`, the time between the first time the span was entered and its
+    /// closing, in milliseconds (`0` if it was never entered).
+    pub fn elapsed_milliseconds(self, enabled: bool) -> Self {
+        let field = DurationField::new("elapsed_milliseconds", DurationUnit::Millis);
+        self.elapsed(enabled.then_some(field))
+    }
+
+    /// `busy_ns`, the time spent inside the span, summed over every time it was entered,
+    /// in nanoseconds.
+    pub fn busy_ns(self, enabled: bool) -> Self {
+        let field = DurationField::new("busy_ns", DurationUnit::Nanos);
+        self.busy(enabled.then_some(field))
+    }
+
+    /// `idle_ns`, the time spent outside of the span between its creation and its closing,
+    /// in nanoseconds.
+    pub fn idle_ns(self, enabled: bool) -> Self {
+        let field = DurationField::new("idle_ns", DurationUnit::Nanos);
+        self.idle(enabled.then_some(field))
+    }
+
+    /// `enter_count`, the number of times the span was entered.
+    pub fn enter_count(mut self, enabled: bool) -> Self {
+        self.enter_count = enabled.then_some(Cow::Borrowed("enter_count"));
+        self
+    }
+
+    /// `wall_ns`, the time between the creation of the span and its closing, in nanoseconds.
+    pub fn wall_ns(self, enabled: bool) -> Self {
+        let field = DurationField::new("wall_ns", DurationUnit::Nanos);
+        self.wall(enabled.then_some(field))
+    }
+
+    /// Like [`elapsed_milliseconds`](Self::elapsed_milliseconds), with a custom name and unit.
+    ///
+    /// Accepts a [`DurationField`] to enable the field, `None` to disable it.
+    pub fn elapsed(mut self, field: impl Into<Option<DurationField>>) -> Self {
+        self.elapsed = field.into();
+        self
+    }
+
+    /// Like [`busy_ns`](Self::busy_ns), with a custom name and unit.
+    pub fn busy(mut self, field: impl Into<Option<DurationField>>) -> Self {
+        self.busy = field.into();
+        self
+    }
+
+    /// Like [`idle_ns`](Self::idle_ns), with a custom name and unit.
+    pub fn idle(mut self, field: impl Into<Option<DurationField>>) -> Self {
+        self.idle = field.into();
+        self
+    }
+
+    /// Like [`wall_ns`](Self::wall_ns), with a custom name and unit.
+    pub fn wall(mut self, field: impl Into<Option<DurationField>>) -> Self {
+        self.wall = field.into();
+        self
+    }
+
+    /// Like [`enter_count`](Self::enter_count), with a custom name.
+    pub fn enter_count_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.enter_count = Some(name.into());
+        self
+    }
+
+    pub(crate) fn is_none(&self) -> bool {
+        *self == Self::none()
+    }
+
+    /// Whether the time of every enter and exit matters, not only the first one.
+    fn tracks_transitions(&self) -> bool {
+        self.busy.is_some() || self.idle.is_some()
+    }
+}
+
+/// The name and the unit of a duration recorded by [`SpanTimings`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DurationField {
+    name: Cow<'static, str>,
+    unit: DurationUnit,
+}
+
+impl DurationField {
+    pub fn new(name: impl Into<Cow<'static, str>>, unit: DurationUnit) -> Self {
+        Self {
+            name: name.into(),
+            unit,
+        }
+    }
+
+    fn to_value(&self, duration: Duration) -> serde_json::Value {
+        match self.unit {
+            DurationUnit::Nanos => u128_to_value(duration.as_nanos()),
+            DurationUnit::Micros => u128_to_value(duration.as_micros()),
+            DurationUnit::Millis => u128_to_value(duration.as_millis()),
+            DurationUnit::Seconds => serde_json::Value::from(duration.as_secs_f64()),
+        }
+    }
+}
+
+/// The unit of a [`DurationField`].
+///
+/// Durations are truncated to whole numbers of their unit, except for seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DurationUnit {
+    Nanos,
+    Micros,
+    Millis,
+    /// Fractional seconds, e.g. `0.0015` for 1.5 milliseconds.
+    Seconds,
+}
+
+/// The timings of a span, kept in its extensions until it is closed.
+///
+/// The clock is only queried for the measurements the [`SpanTimings`] need.
+#[derive(Debug, Default)]
+pub(crate) struct Timings {
+    created: Option<Instant>,
+    first_entered: Option<Instant>,
+    /// When the span was last entered or exited, if transitions are tracked.
+    last_transition: Option<Instant>,
+    /// A span can be entered again while it is entered already: only the outermost enter
+    /// and exit count for busy and idle time.
+    depth: usize,
+    busy: Duration,
+    idle: Duration,
+    enter_count: u64,
+}
+
+impl Timings {
+    pub(crate) fn new(span_timings: &SpanTimings, clock: &dyn Clock) -> Self {
+        let mut timings = Self::default();
+        if span_timings.idle.is_some() || span_timings.wall.is_some() {
+            let now = clock.instant();
+            timings.created = Some(now);
+            timings.last_transition = Some(now);
+        }
+        timings
+    }
+
+    pub(crate) fn enter(&mut self, span_timings: &SpanTimings, clock: &dyn Clock) {
+        self.enter_count += 1;
+        self.depth += 1;
+        let transition = span_timings.tracks_transitions() && self.depth == 1;
+        let first = span_timings.elapsed.is_some() && self.first_entered.is_none();
+        if !transition && !first {
+            return;
+        }
+        let now = clock.instant();
+        self.first_entered.get_or_insert(now);
+        if transition {
+            if let Some(last) = self.last_transition {
+                self.idle += now.saturating_duration_since(last);
+            }
+            self.last_transition = Some(now);
+        }
+    }
+
+    pub(crate) fn exit(&mut self, span_timings: &SpanTimings, clock: &dyn Clock) {
+        self.depth = self.depth.saturating_sub(1);
+        if !span_timings.tracks_transitions() || self.depth > 0 {
+            return;
+        }
+        let now = clock.instant();
+        if let Some(last) = self.last_transition {
+            self.busy += now.saturating_duration_since(last);
+        }
+        self.last_transition = Some(now);
+    }
+
+    /// Hand the timing fields over to `record`, as the span is being closed.
+    pub(crate) fn close(
+        mut self,
+        span_timings: &SpanTimings,
+        clock: &dyn Clock,
+        mut record: impl FnMut(Cow<'static, str>, serde_json::Value),
+    ) {
+        if span_timings.is_none() {
+            return;
+        }
+        let now = clock.instant();
+        if let Some(last) = self.last_transition {
+            // A span is not supposed to be closed while it is entered, but better safe than sorry.
+            let pending = now.saturating_duration_since(last);
+            if self.depth == 0 {
+                self.idle += pending;
+            } else {
+                self.busy += pending;
+            }
+        }
+        let since = |instant: Option<Instant>| {
+            instant.map_or(Duration::ZERO, |instant| {
+                now.saturating_duration_since(instant)
+            })
+        };
+
+        let durations = [
+            (&span_timings.elapsed, since(self.first_entered)),
+            (&span_timings.busy, self.busy),
+            (&span_timings.idle, self.idle),
+            (&span_timings.wall, since(self.created)),
+        ];
+        for (field, duration) in durations {
+            if let Some(field) = field {
+                record(field.name.clone(), field.to_value(duration));
+            }
+        }
+        if let Some(name) = &span_timings.enter_count {
+            record(name.clone(), serde_json::Value::from(self.enter_count));
+        }
+    }
+}
+
+/// With the `arbitrary-precision` feature durations are recorded losslessly.
+#[cfg(feature = "arbitrary-precision")]
+fn u128_to_value(value: u128) -> serde_json::Value {
+    serde_json::to_value(value).unwrap_or_else(|_| serde_json::Value::from(u64::MAX))
+}
+
+/// Without the `arbitrary-precision` feature u128 values are not supported: durations are
+/// clamped to `u64::MAX`, which is more than 584 years in nanoseconds.
+#[cfg(not(feature = "arbitrary-precision"))]
+fn u128_to_value(value: u128) -> serde_json::Value {
+    use std::convert::TryInto;
+
+    let value: u64 = value.try_into().unwrap_or(u64::MAX);
+    serde_json::Value::from(value)
+}
@@ -1,10 +1,15 @@
-use std::collections::HashMap;
+use crate::clock::{Clock, SystemClock};
+use crate::span_timings::{SpanTimings, Timings};
+use std::backtrace::{Backtrace, BacktraceStatus};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::fmt;
-use std::time::Instant;
+use std::sync::{Arc, OnceLock};
 use tracing::field::{Field, Visit};
 use tracing::span::{Attributes, Record};
 use tracing::{Id, Subscriber};
 use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::{LookupSpan, SpanRef};
 use tracing_subscriber::Layer;
 
 /// This layer is only concerned with information storage, it does not do any formatting or provide any output.
@@ -12,28 +17,649 @@ use tracing_subscriber::Layer;
 /// It's purpose is to store the fields associated to spans in an easy-to-consume format
 /// for downstream layers concerned with emitting a formatted representation of
 /// spans or events.
-#[derive(Clone, Debug)]
-pub struct JsonStorageLayer;
+///
+/// ```rust
+/// use tracing_bunyan_formatter::JsonStorageLayer;
+/// use tracing_subscriber::Registry;
+/// use tracing_subscriber::layer::SubscriberExt;
+///
+/// let subscriber = Registry::default().with(JsonStorageLayer::default());
+/// ```
+#[derive(Clone)]
+pub struct JsonStorageLayer {
+    clock: Arc<dyn Clock>,
+    inheritance: Arc<Inheritance>,
+    span_timings: SpanTimings,
+    error_backtraces: bool,
+}
+
+impl JsonStorageLayer {
+    /// Measure the duration of spans using `clock` instead of the system clock.
+    ///
+    /// See [`Clock`] for more details.
+    pub fn with_clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Choose the timing fields recorded on spans when they are closed.
+    ///
+    /// See [`SpanTimings`] for more details.
+    pub fn with_span_timings(mut self, span_timings: SpanTimings) -> Self {
+        self.span_timings = span_timings;
+        self
+    }
+
+    /// Choose which fields child spans (and events) inherit from their parent span.
+    ///
+    /// All fields are inherited by default.
+    pub fn with_inheritance(mut self, inheritance: FieldInheritance) -> Self {
+        Arc::make_mut(&mut self.inheritance).policy = inheritance;
+        self
+    }
+
+    /// Keep the fields whose name starts with `prefix` on the span that recorded them,
+    /// whatever the [`FieldInheritance`] policy.
+    ///
+    /// The prefix is stripped from the name of the field.
+    /// It only applies to span fields: event fields are never inherited, and are formatted
+    /// with their name as is, prefix included.
+    /// ```rust
+    /// use tracing_bunyan_formatter::JsonStorageLayer;
+    /// use tracing_subscriber::Registry;
+    /// use tracing_subscriber::layer::SubscriberExt;
+    ///
+    /// let subscriber = Registry::default()
+    ///     .with(JsonStorageLayer::default().with_local_field_prefix("local."));
+    ///
+    /// tracing::subscriber::with_default(subscriber, || {
+    ///     // `payload` is recorded on `request` only, not on the spans and events within it.
+    ///     let _request = tracing::info_span!("request", local.payload = "...").entered();
+    ///     // Formatted as `local.attempt`.
+    ///     tracing::info!(local.attempt = 1, "retrying");
+    /// });
+    /// ```
+    pub fn with_local_field_prefix(mut self, prefix: impl Into<String>) -> Self {
+        Arc::make_mut(&mut self.inheritance).local_field_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Append a backtrace to the `stack` of the errors recorded as span fields, if enabled via
+    /// `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`.
+    ///
+    /// The backtrace is captured when the error is recorded: it shows where the error was
+    /// logged (including the frames of `tracing` and of this crate), not where it was created.
+    /// Capturing and symbolizing a backtrace is expensive.
+    ///
+    /// Disabled by default. Use [`BunyanFormattingLayerBuilder::error_backtraces`](crate::BunyanFormattingLayerBuilder::error_backtraces)
+    /// for the errors recorded as event fields.
+    pub fn with_error_backtraces(mut self, enabled: bool) -> Self {
+        self.error_backtraces = enabled;
+        self
+    }
+}
+
+impl Default for JsonStorageLayer {
+    fn default() -> Self {
+        Self {
+            clock: Arc::new(SystemClock),
+            inheritance: Arc::default(),
+            span_timings: SpanTimings::default(),
+            error_backtraces: false,
+        }
+    }
+}
+
+/// Which fields child spans and events inherit from their parent span,
+/// see [`JsonStorageLayer::with_inheritance`].
+///
+/// A field that is not inherited is still recorded on the span that declared it.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum FieldInheritance {
+    /// Every field is inherited.
+    #[default]
+    All,
+    /// No field is inherited.
+    None,
+    /// Only the fields with one of these names are inherited.
+    Allow(HashSet<String>),
+    /// The fields with one of these names are not inherited.
+    Deny(HashSet<String>),
+}
+
+impl FieldInheritance {
+    /// Inherit only the fields with one of these names.
+    pub fn allow<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        Self::Allow(keys.into_iter().map(Into::into).collect())
+    }
+
+    /// Inherit every field but the ones with one of these names.
+    pub fn deny<I, K>(keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<String>,
+    {
+        Self::Deny(keys.into_iter().map(Into::into).collect())
+    }
+
+    fn inherits(&self, key: &str) -> bool {
+        match self {
+            FieldInheritance::All => true,
+            FieldInheritance::None => false,
+            FieldInheritance::Allow(keys) => keys.contains(key),
+            FieldInheritance::Deny(keys) => !keys.contains(key),
+        }
+    }
+}
+
+/// The inheritance settings of a [`JsonStorageLayer`], shared with the storage of its spans so
+/// that fields inserted by other layers follow them too.
+#[derive(Clone, Debug, Default)]
+struct Inheritance {
+    policy: FieldInheritance,
+    local_field_prefix: Option<String>,
+}
+
+impl Inheritance {
+    /// Strip the local prefix from `key`, if it has it, and tell whether the descendants of
+    /// the span see the field.
+    fn classify<'a>(&self, key: Cow<'a, str>) -> (Cow<'a, str>, bool) {
+        match self.local_field_prefix.as_deref() {
+            Some(prefix) if key.starts_with(prefix) => {
+                let key = match key {
+                    Cow::Borrowed(key) => Cow::Borrowed(&key[prefix.len()..]),
+                    Cow::Owned(mut key) => {
+                        key.drain(..prefix.len());
+                        Cow::Owned(key)
+                    }
+                };
+                (key, false)
+            }
+            _ => {
+                let inheritable = self.policy.inherits(&key);
+                (key, inheritable)
+            }
+        }
+    }
+}
+
+impl fmt::Debug for JsonStorageLayer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JsonStorageLayer").finish_non_exhaustive()
+    }
+}
 
 /// `JsonStorage` will collect information about a span when it's created (`new_span` handler)
 /// or when new records are attached to it (`on_record` handler) and store it in its `extensions`
 /// for future retrieval from other layers interested in formatting or further enrichment.
 ///
-/// We are re-implementing (well, copy-pasting, apart from using an HashMap instead of a BTreeMap)
-/// `JsonVisitor` from `tracing-subscriber` given that we can't access/insert/iterate over
-/// the underlying BTreeMap using its public API.
+/// We are re-implementing (well, copy-pasting, apart from keeping the fields in declaration order
+/// instead of using a BTreeMap) `JsonVisitor` from `tracing-subscriber` given that we can't
+/// access/insert/iterate over the underlying BTreeMap using its public API.
 ///
 /// For spans, we also store the duration of each span with the `elapsed_milliseconds` key using
-/// the `on_exit`/`on_enter` handlers.
+/// the `on_enter`/`on_close` handlers, along with the other timing fields enabled via
+/// [`JsonStorageLayer::with_span_timings`].
+///
+/// Each span also keeps track of the id of the root of its span tree, its `trace_id`.
+///
+/// Spans inherit the fields of their ancestors, as they were when the span was created,
+/// unless [`JsonStorageLayer::with_inheritance`] says otherwise.
+/// Inherited fields are not copied: they are shared with the ancestors and resolved when
+/// reading them, which keeps the creation of a span proportional to the number of its own fields.
+/// Use [`JsonStorage::resolve_live`] to get the fields of the ancestors as they are now instead.
 #[derive(Clone, Debug)]
 pub struct JsonStorage<'a> {
-    values: HashMap<&'a str, serde_json::Value>,
+    /// The fields recorded on the span itself.
+    /// They are copied on write if a child span holds on to them.
+    values: Arc<Fields<'a>>,
+    inherited: Option<Arc<Inherited<'a>>>,
+    trace_id: Option<Id>,
+    /// The inheritance settings of the layer that created the storage, if any.
+    /// Without them, every field is inherited.
+    inheritance: Option<Arc<Inheritance>>,
+    /// Whether recording an error captures a backtrace of the logging site.
+    error_backtraces: bool,
+}
+
+/// Fields in declaration order.
+///
+/// Spans and events rarely have more than a handful of fields: a linear scan to look them up
+/// is cheaper than hashing.
+type Fields<'a> = Vec<Entry<'a>>;
+
+#[derive(Clone, Debug)]
+struct Entry<'a> {
+    key: Cow<'a, str>,
+    /// `None` marks a field removed via [`JsonStorage::remove`]: it hides the field of
+    /// the same name inherited from an ancestor.
+    value: Option<serde_json::Value>,
+    /// Whether the descendants of the span see this field.
+    inheritable: bool,
+}
+
+/// Where the value of a field of a [`JsonStorage`] comes from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FieldSource<'s> {
+    /// The field has been recorded on the span itself (or on the event).
+    Own,
+    /// The field has been inherited from the ancestor span with the given id.
+    Inherited(&'s Id),
+}
+
+/// The fields of an ancestor span, as they were when its descendant was created.
+#[derive(Debug)]
+struct Inherited<'a> {
+    span_id: Id,
+    values: Arc<Fields<'a>>,
+    parent: Option<Arc<Inherited<'a>>>,
+    /// The number of ancestors linked via `parent`.
+    generation: usize,
+    /// The fields the descendants of the span see, resolved on the first read.
+    visible: OnceLock<Vec<Visible>>,
+}
+
+/// A field seen by the descendants of a span: the `index`-th field of the ancestor linked
+/// at `generation`.
+#[derive(Clone, Copy, Debug)]
+struct Visible {
+    generation: usize,
+    index: usize,
+}
+
+impl<'a> Inherited<'a> {
+    fn new(span_id: Id, values: Arc<Fields<'a>>, parent: Option<Arc<Inherited<'a>>>) -> Self {
+        Self {
+            span_id,
+            values,
+            generation: parent.as_ref().map_or(0, |parent| parent.generation + 1),
+            parent,
+            visible: OnceLock::new(),
+        }
+    }
+
+    /// The ancestors linked via `parent`, from the root span down to this one.
+    fn lineage(&self) -> Vec<&Self> {
+        let mut lineage: Vec<_> =
+            std::iter::successors(Some(self), |ancestor| ancestor.parent.as_deref()).collect();
+        lineage.reverse();
+        lineage
+    }
+
+    /// The fields the descendants of the span see, in the order of [`JsonStorage::iter`]:
+    /// each key once, from the closest span having it, leaving out removed fields.
+    ///
+    /// They are resolved once per span, so that reading the fields of a span stays linear
+    /// in the depth of its span tree.
+    fn visible(&self) -> &[Visible] {
+        if let Some(visible) = self.visible.get() {
+            return visible;
+        }
+        // Resolve the ancestors first, from the root down, to avoid deep recursion.
+        let lineage = self.lineage();
+        let pending = lineage
+            .iter()
+            .position(|ancestor| ancestor.visible.get().is_none())
+            .unwrap_or(lineage.len());
+        for ancestor in &lineage[pending..] {
+            ancestor
+                .visible
+                .get_or_init(|| ancestor.resolve_visible(&lineage));
+        }
+        self.visible.get_or_init(|| self.resolve_visible(&lineage))
+    }
+
+    /// Compute the fields the descendants of the span see, once the ones of its parent are.
+    fn resolve_visible(&self, lineage: &[&Self]) -> Vec<Visible> {
+        let parent = self
+            .parent
+            .as_deref()
+            .and_then(|parent| parent.visible.get());
+        let mut visible: Vec<_> = parent
+            .into_iter()
+            .flatten()
+            .filter(|field| {
+                let key = &lineage[field.generation].values[field.index].key;
+                find(&self.values, key, true).is_none()
+            })
+            .copied()
+            .collect();
+        visible.extend(
+            self.values
+                .iter()
+                .enumerate()
+                .filter(|(_, entry)| entry.inheritable && entry.value.is_some())
+                .map(|(index, _)| Visible {
+                    generation: self.generation,
+                    index,
+                }),
+        );
+        visible
+    }
 }
 
 impl<'a> JsonStorage<'a> {
-    /// Get the set of stored values, as a set of keys and JSON values.
-    pub fn values(&self) -> &HashMap<&'a str, serde_json::Value> {
-        &self.values
+    /// Get the set of stored values, as a set of keys and JSON values, including the ones
+    /// inherited from the ancestors of the span.
+    ///
+    /// The map is built on every call, and it borrows the values instead of returning a
+    /// reference to a map held by the storage, as it did before fields were inherited lazily.
+    #[deprecated(
+        since = "0.4.0",
+        note = "use `get` to look up a few fields and `iter` to go through all of them"
+    )]
+    pub fn values(&self) -> HashMap<&str, &serde_json::Value> {
+        self.iter().collect()
+    }
+
+    /// Get the value of the field named `key`, looking it up in the ancestors of the span
+    /// if the span itself does not have it.
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        find(&self.values, key, false)
+            .or_else(|| {
+                self.ancestors()
+                    .find_map(|ancestor| find(&ancestor.values, key, true))
+            })
+            .and_then(Option::as_ref)
+    }
+
+    /// Get the value of the field named `key` if it is a string.
+    pub fn get_str(&self, key: &str) -> Option<&str> {
+        self.get(key).and_then(serde_json::Value::as_str)
+    }
+
+    /// Get the value of the field named `key` if it is an integer fitting in an `i64`.
+    pub fn get_i64(&self, key: &str) -> Option<i64> {
+        self.get(key).and_then(serde_json::Value::as_i64)
+    }
+
+    /// Get the value of the field named `key` if it is an integer fitting in a `u64`.
+    pub fn get_u64(&self, key: &str) -> Option<u64> {
+        self.get(key).and_then(serde_json::Value::as_u64)
+    }
+
+    /// Get the value of the field named `key` if it is a number, as an `f64`.
+    pub fn get_f64(&self, key: &str) -> Option<f64> {
+        self.get(key).and_then(serde_json::Value::as_f64)
+    }
+
+    /// Get the value of the field named `key` if it is a boolean.
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(serde_json::Value::as_bool)
+    }
+
+    /// Check if there is a field named `key`, recorded on the span or inherited.
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Iterate over the stored values, including the ones inherited from the ancestors of the
+    /// span, in declaration order: the fields of the root span come first, the ones recorded
+    /// on the span itself last.
+    ///
+    /// Each key is yielded once: fields recorded on the span override the ones of its
+    /// ancestors.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &serde_json::Value)> + '_ {
+        self.iter_with_source().map(|(key, value, _)| (key, value))
+    }
+
+    /// Like [`iter`](Self::iter), telling where each field comes from.
+    pub fn iter_with_source(
+        &self,
+    ) -> impl Iterator<Item = (&str, &serde_json::Value, FieldSource<'_>)> + '_ {
+        let (ancestors, inherited) = match self.inherited.as_deref() {
+            Some(inherited) => (inherited.lineage(), inherited.visible()),
+            None => (Vec::new(), &[][..]),
+        };
+        Iter {
+            own: &self.values,
+            ancestors,
+            inherited,
+            position: 0,
+        }
+    }
+
+    /// Iterate over the fields recorded on the span itself, in declaration order, leaving out
+    /// the ones inherited from its ancestors.
+    pub fn own_fields(&self) -> impl Iterator<Item = (&str, &serde_json::Value)> + '_ {
+        self.values
+            .iter()
+            .filter_map(|entry| Some((entry.key.as_ref(), entry.value.as_ref()?)))
+    }
+
+    /// Tell where the field named `key` comes from, if there is such a field.
+    pub fn source(&self, key: &str) -> Option<FieldSource<'_>> {
+        if let Some(value) = find(&self.values, key, false) {
+            return value.as_ref().map(|_| FieldSource::Own);
+        }
+        self.ancestors().find_map(|ancestor| {
+            find(&ancestor.values, key, true).map(|value| {
+                value
+                    .as_ref()
+                    .map(|_| FieldSource::Inherited(&ancestor.span_id))
+            })
+        })?
+    }
+
+    /// Set the value of the field named `key`, overriding any field with the same name
+    /// inherited from an ancestor.
+    ///
+    /// Returns the previous value recorded on the span itself, if any.
+    ///
+    /// The field follows the [`JsonStorageLayer::with_inheritance`] policy and the
+    /// [`JsonStorageLayer::with_local_field_prefix`] of the layer that created the storage,
+    /// like the fields recorded via `tracing`.
+    ///
+    /// This is meant for layers enriching spans with fields of their own, which must be
+    /// registered after [`JsonStorageLayer`]:
+    /// ```rust
+    /// use tracing::span::{Attributes, Id};
+    /// use tracing::Subscriber;
+    /// use tracing_bunyan_formatter::{JsonStorage, JsonStorageLayer};
+    /// use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+    /// use tracing_subscriber::registry::LookupSpan;
+    ///
+    /// struct TenantLayer;
+    ///
+    /// impl<S: Subscriber + for<'a> LookupSpan<'a>> Layer<S> for TenantLayer {
+    ///     fn on_new_span(&self, _attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+    ///         let span = ctx.span(id).expect("Span not found, this is a bug");
+    ///         let mut extensions = span.extensions_mut();
+    ///         if let Some(storage) = extensions.get_mut::<JsonStorage>() {
+    ///             let key = format!("{}_tenant", span.name());
+    ///             storage.insert(key, "acme");
+    ///         }
+    ///     }
+    /// }
+    ///
+    /// let subscriber = tracing_subscriber::registry()
+    ///     .with(JsonStorageLayer::default())
+    ///     .with(TenantLayer);
+    /// ```
+    pub fn insert(
+        &mut self,
+        key: impl Into<Cow<'a, str>>,
+        value: impl Into<serde_json::Value>,
+    ) -> Option<serde_json::Value> {
+        let (key, inheritable) = match self.inheritance.as_deref() {
+            Some(inheritance) => inheritance.classify(key.into()),
+            None => (key.into(), true),
+        };
+        let value = Some(value.into());
+        let values = Arc::make_mut(&mut self.values);
+        match values.iter_mut().find(|entry| entry.key == key) {
+            Some(entry) => {
+                entry.inheritable &= inheritable;
+                std::mem::replace(&mut entry.value, value)
+            }
+            None => {
+                values.push(Entry {
+                    key,
+                    value,
+                    inheritable,
+                });
+                None
+            }
+        }
+    }
+
+    /// Remove the field named `key`, whether it was recorded on the span itself or inherited
+    /// from one of its ancestors (which keep it).
+    ///
+    /// Returns the value the field had, if any.
+    pub fn remove(&mut self, key: &str) -> Option<serde_json::Value> {
+        let inherited = self
+            .ancestors()
+            .find_map(|ancestor| find(&ancestor.values, key, true))
+            .cloned()
+            .flatten();
+        let values = Arc::make_mut(&mut self.values);
+        let own = values.iter().position(|entry| entry.key == key);
+        match (own, inherited.is_some()) {
+            // The inherited field must stay hidden.
+            (Some(i), true) => values[i].value.take(),
+            (Some(i), false) => values.remove(i).value,
+            (None, true) => {
+                values.push(Entry {
+                    key: Cow::Owned(key.to_owned()),
+                    value: None,
+                    inheritable: true,
+                });
+                inherited
+            }
+            (None, false) => None,
+        }
+    }
+
+    /// Get the id of the root span of the span tree this span belongs to.
+    ///
+    /// It is `None` for the storage of events.
+    pub fn trace_id(&self) -> Option<&Id> {
+        self.trace_id.as_ref()
+    }
+
+    /// Get a copy of this storage, the one of `span`, with the fields it inherits resolved
+    /// from the ancestors of `span` as they are now rather than as they were when `span` was
+    /// created.
+    ///
+    /// Fields recorded on an ancestor after the creation of `span` are included, while
+    /// [`JsonStorageLayer::with_inheritance`] still applies.
+    ///
+    /// The ancestors of `span` are looked up on every call, but not `span` itself: call it
+    /// while holding the extensions of `span`, which must not be locked a second time.
+    pub fn resolve_live<S>(&self, span: &SpanRef<'_, S>) -> Self
+    where
+        S: for<'l> LookupSpan<'l>,
+    {
+        let mut inherited = None;
+        for ancestor in span
+            .parent()
+            .into_iter()
+            .flat_map(|parent| parent.scope().from_root())
+        {
+            let extensions = ancestor.extensions();
+            if let Some(fields) = extensions.get::<JsonStorage>() {
+                inherited = fields.inherit(&ancestor.id(), inherited);
+            }
+        }
+        Self {
+            values: Arc::clone(&self.values),
+            inherited,
+            trace_id: self.trace_id.clone(),
+            inheritance: self.inheritance.clone(),
+            error_backtraces: self.error_backtraces,
+        }
+    }
+
+    fn ancestors(&self) -> impl Iterator<Item = &Inherited<'a>> {
+        std::iter::successors(self.inherited.as_deref(), |ancestor| {
+            ancestor.parent.as_deref()
+        })
+    }
+
+    /// Capture a backtrace when recording errors, see [`JsonStorageLayer::with_error_backtraces`].
+    pub(crate) fn capture_error_backtraces(&mut self, enabled: bool) {
+        self.error_backtraces = enabled;
+    }
+
+    /// Start the storage of a child span, sharing the fields of this one, which is the storage
+    /// of the span identified by `id`.
+    fn child(&self, id: &Id) -> Self {
+        Self {
+            values: Arc::default(),
+            inherited: self.inherit(id, self.inherited.clone()),
+            trace_id: self.trace_id.clone(),
+            inheritance: self.inheritance.clone(),
+            error_backtraces: self.error_backtraces,
+        }
+    }
+
+    /// Link the fields of this storage, the one of the span identified by `id`, to the
+    /// fields its descendants inherit from the ancestors of the span, `parent`.
+    fn inherit(&self, id: &Id, parent: Option<Arc<Inherited<'a>>>) -> Option<Arc<Inherited<'a>>> {
+        if !self.values.iter().any(|entry| entry.inheritable) {
+            return parent;
+        }
+        Some(Arc::new(Inherited::new(
+            id.clone(),
+            Arc::clone(&self.values),
+            parent,
+        )))
+    }
+}
+
+/// Find the field named `key`: `Some(None)` if it has been removed.
+///
+/// `inherited` leaves out the fields the descendants of the span do not see.
+fn find<'f>(
+    fields: &'f [Entry<'_>],
+    key: &str,
+    inherited: bool,
+) -> Option<&'f Option<serde_json::Value>> {
+    fields
+        .iter()
+        .find(|entry| entry.key == key && (entry.inheritable || !inherited))
+        .map(|entry| &entry.value)
+}
+
+/// Iterates over the fields of a span and of its ancestors, see [`JsonStorage::iter`].
+struct Iter<'s, 'a> {
+    own: &'s [Entry<'a>],
+    /// The ancestors, from the root span down to the parent.
+    ancestors: Vec<&'s Inherited<'a>>,
+    /// The inherited fields left to go through, before the fields of the span itself.
+    inherited: &'s [Visible],
+    position: usize,
+}
+
+impl<'s> Iterator for Iter<'s, '_> {
+    type Item = (&'s str, &'s serde_json::Value, FieldSource<'s>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        // Skip the inherited fields overridden or removed by the span itself.
+        while let Some((field, rest)) = self.inherited.split_first() {
+            self.inherited = rest;
+            let ancestor = self.ancestors[field.generation];
+            let entry = &ancestor.values[field.index];
+            if find(self.own, &entry.key, false).is_none() {
+                if let Some(value) = &entry.value {
+                    let source = FieldSource::Inherited(&ancestor.span_id);
+                    return Some((entry.key.as_ref(), value, source));
+                }
+            }
+        }
+        // Skip the removed fields of the span itself.
+        while let Some(entry) = self.own.get(self.position) {
+            self.position += 1;
+            if let Some(value) = &entry.value {
+                return Some((entry.key.as_ref(), value, FieldSource::Own));
+            }
+        }
+        None
     }
 }
 
@@ -41,35 +667,75 @@ impl<'a> JsonStorage<'a> {
 impl Default for JsonStorage<'_> {
     fn default() -> Self {
         Self {
-            values: HashMap::new(),
+            values: Arc::default(),
+            inherited: None,
+            trace_id: None,
+            inheritance: None,
+            error_backtraces: false,
         }
     }
 }
 
-/// Taken verbatim from tracing-subscriber
+/// Taken verbatim from tracing-subscriber, apart from floats, 128-bit integers and errors.
 impl Visit for JsonStorage<'_> {
+    /// Visit a 64-bit floating point value.
+    ///
+    /// JSON has no representation for NaN and infinities: they are recorded as the strings
+    /// `"NaN"`, `"inf"` and `"-inf"` instead of being dropped.
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        let value = serde_json::Number::from_f64(value)
+            .map(serde_json::Value::Number)
+            .unwrap_or_else(|| serde_json::Value::from(value.to_string()));
+        self.insert(field.name(), value);
+    }
+
     /// Visit a signed 64-bit integer value.
     fn record_i64(&mut self, field: &Field, value: i64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit an unsigned 64-bit integer value.
     fn record_u64(&mut self, field: &Field, value: u64) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
+    }
+
+    /// Visit a signed 128-bit integer value.
+    ///
+    /// Without the `arbitrary-precision` feature values that do not fit in 64 bits
+    /// are recorded as strings to avoid losing precision.
+    fn record_i128(&mut self, field: &Field, value: i128) {
+        let value = serde_json::to_value(value)
+            .unwrap_or_else(|_| serde_json::Value::from(value.to_string()));
+        self.insert(field.name(), value);
+    }
+
+    /// Visit an unsigned 128-bit integer value.
+    ///
+    /// Without the `arbitrary-precision` feature values that do not fit in 64 bits
+    /// are recorded as strings to avoid losing precision.
+    fn record_u128(&mut self, field: &Field, value: u128) {
+        let value = serde_json::to_value(value)
+            .unwrap_or_else(|_| serde_json::Value::from(value.to_string()));
+        self.insert(field.name(), value);
     }
 
     /// Visit a boolean value.
     fn record_bool(&mut self, field: &Field, value: bool) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
     }
 
     /// Visit a string value.
     fn record_str(&mut self, field: &Field, value: &str) {
-        self.values
-            .insert(field.name(), serde_json::Value::from(value));
+        self.insert(field.name(), serde_json::Value::from(value));
+    }
+
+    /// Visit an error.
+    ///
+    /// The error is recorded using the shape Bunyan viewers expect for `err` fields
+    /// (its `message`, `name`, `stack` and `sources`): name your field `err` to get them
+    /// rendered nicely.
+    fn record_error(&mut self, field: &Field, value: &(dyn std::error::Error + 'static)) {
+        self.insert(field.name(), error_to_value(value, self.error_backtraces));
     }
 
     fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
@@ -77,12 +743,10 @@ impl Visit for JsonStorage<'_> {
             // Skip fields that are actually log metadata that have already been handled
             name if name.starts_with("log.") => (),
             name if name.starts_with("r#") => {
-                self.values
-                    .insert(&name[2..], serde_json::Value::from(format!("{:?}", value)));
+                self.insert(&name[2..], serde_json::Value::from(format!("{:?}", value)));
             }
             name => {
-                self.values
-                    .insert(name, serde_json::Value::from(format!("{:?}", value)));
+                self.insert(name, serde_json::Value::from(format!("{:?}", value)));
             }
         };
     }
@@ -97,27 +761,36 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
     fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
         let span = ctx.span(id).expect("Span not found, this is a bug");
 
-        // We want to inherit the fields from the parent span, if there is one.
+        // We want to inherit the fields (and the trace id) from the parent span, if there is one.
         let mut visitor = if let Some(parent_span) = span.parent() {
             // Extensions can be used to associate arbitrary data to a span.
             // We'll use it to store our representation of its fields.
-            // We create a copy of the parent visitor!
-            let mut extensions = parent_span.extensions_mut();
+            // The child shares the fields of the parent visitor, it does not copy them.
+            let extensions = parent_span.extensions();
             extensions
-                .get_mut::<JsonStorage>()
-                .map(|v| v.to_owned())
+                .get::<JsonStorage>()
+                .map(|parent| parent.child(&parent_span.id()))
                 .unwrap_or_default()
         } else {
-            JsonStorage::default()
+            // This is a root span: it starts a new trace.
+            JsonStorage {
+                trace_id: Some(id.clone()),
+                ..JsonStorage::default()
+            }
         };
 
         let mut extensions = span.extensions_mut();
 
         // Register all fields.
         // Fields on the new span should override fields on the parent span if there is a conflict.
+        visitor.inheritance = Some(Arc::clone(&self.inheritance));
+        visitor.capture_error_backtraces(self.error_backtraces);
         attrs.record(&mut visitor);
         // Associate the visitor with the Span for future usage via the Span's extensions
         extensions.insert(visitor);
+        if !self.span_timings.is_none() {
+            extensions.insert(Timings::new(&self.span_timings, self.clock.as_ref()));
+        }
     }
 
     fn on_record(&self, span: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
@@ -134,48 +807,83 @@ impl<S: Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>> Layer
         values.record(visitor);
     }
 
-    /// When we enter a span **for the first time** save the timestamp in its extensions.
+    /// Keep track of the time spent inside the span.
     fn on_enter(&self, span: &Id, ctx: Context<'_, S>) {
         let span = ctx.span(span).expect("Span not found, this is a bug");
 
         let mut extensions = span.extensions_mut();
-        if extensions.get_mut::<Instant>().is_none() {
-            extensions.insert(Instant::now());
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            timings.enter(&self.span_timings, self.clock.as_ref());
         }
     }
 
-    /// When we close a span, register how long it took in milliseconds.
-    fn on_close(&self, span: Id, ctx: Context<'_, S>) {
-        let span = ctx.span(&span).expect("Span not found, this is a bug");
+    /// Keep track of the time spent outside of the span.
+    fn on_exit(&self, span: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(span).expect("Span not found, this is a bug");
 
-        // Using a block to drop the immutable reference to extensions
-        // given that we want to borrow it mutably just below
-        let elapsed_milliseconds = {
-            let extensions = span.extensions();
-            extensions
-                .get::<Instant>()
-                .map(|i| i.elapsed().as_millis())
-                // If `Instant` is not in the span extensions it means that the span was never
-                // entered into.
-                .unwrap_or(0)
-        };
+        let mut extensions = span.extensions_mut();
+        if let Some(timings) = extensions.get_mut::<Timings>() {
+            timings.exit(&self.span_timings, self.clock.as_ref());
+        }
+    }
 
-        #[cfg(not(feature = "arbitrary-precision"))]
-        // without the arbitrary_precision feature u128 values are not supported,
-        // but u64 is still more than enough for our purposes
-        let elapsed_milliseconds: u64 = {
-            use std::convert::TryInto;
+    /// When we close a span, register how long it took.
+    fn on_close(&self, span: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&span).expect("Span not found, this is a bug");
 
-            elapsed_milliseconds.try_into().unwrap_or_default()
+        let mut extensions = span.extensions_mut();
+        let Some(timings) = extensions.remove::<Timings>() else {
+            return;
         };
-
-        let mut extensions_mut = span.extensions_mut();
-        let visitor = extensions_mut
+        let visitor = extensions
             .get_mut::<JsonStorage>()
             .expect("Visitor not found on 'record', this is a bug");
+        timings.close(&self.span_timings, self.clock.as_ref(), |key, value| {
+            visitor.insert(key, value);
+        });
+    }
+}
+
+/// Convert an error into a JSON object following the conventions of
+/// [Bunyan's `err` serializer](https://github.com/trentm/node-bunyan#recommendedbest-practice-fields):
+/// - `message`, the `Display` representation of the error;
+/// - `name`, for `std::io::Error`s only: Rust does not give us the name of the concrete type
+///   behind any other `dyn Error`, and guessing it from its `Debug` representation gets
+///   the variant of error enums instead;
+/// - `code`, the kind of the error, for `std::io::Error`s only;
+/// - `stack`, the message of the error followed by the messages of its causes and, if
+///   `backtrace` is set and backtraces are enabled via `RUST_BACKTRACE` or `RUST_LIB_BACKTRACE`,
+///   the backtrace captured when recording it;
+/// - `sources`, the messages of the errors in the `source()` chain, outermost first.
+fn error_to_value(error: &(dyn std::error::Error + 'static), backtrace: bool) -> serde_json::Value {
+    let message = error.to_string();
 
-        if let Ok(elapsed) = serde_json::to_value(elapsed_milliseconds) {
-            visitor.values.insert("elapsed_milliseconds", elapsed);
+    let mut sources = Vec::new();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        sources.push(cause.to_string());
+        source = cause.source();
+    }
+
+    let mut stack = message.clone();
+    for cause in &sources {
+        stack.push_str("\nCaused by: ");
+        stack.push_str(cause);
+    }
+    if backtrace {
+        let backtrace = Backtrace::capture();
+        if backtrace.status() == BacktraceStatus::Captured {
+            stack.push_str(&format!("\n{}", backtrace));
         }
     }
+
+    let mut err = serde_json::Map::new();
+    err.insert("message".into(), message.into());
+    if let Some(io_error) = error.downcast_ref::<std::io::Error>() {
+        err.insert("name".into(), "std::io::Error".into());
+        err.insert("code".into(), format!("{:?}", io_error.kind()).into());
+    }
+    err.insert("stack".into(), stack.into());
+    err.insert("sources".into(), sources.into());
+    serde_json::Value::Object(err)
 }
@@ -0,0 +1,189 @@
+use serde::ser::{Serialize, Serializer};
+use std::convert::TryFrom;
+use std::fmt;
+use time::{OffsetDateTime, UtcOffset};
+
+/// How the `time` field of each record is formatted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum TimestampFormat {
+    /// An [RFC 3339](https://tools.ietf.org/html/rfc3339) date-time string, as mandated by the
+    /// [Bunyan format](https://github.com/trentm/node-bunyan#core-fields).
+    Rfc3339 {
+        precision: SubsecondPrecision,
+        offset: TimestampOffset,
+    },
+    /// The time elapsed since the Unix epoch, as an integer.
+    ///
+    /// Be aware that Bunyan viewers expect `time` to be a string and will not understand it.
+    UnixEpoch(EpochUnit),
+}
+
+/// RFC 3339 timestamps in UTC, with as many sub-second digits as needed.
+impl Default for TimestampFormat {
+    fn default() -> Self {
+        TimestampFormat::Rfc3339 {
+            precision: SubsecondPrecision::Auto,
+            offset: TimestampOffset::Utc,
+        }
+    }
+}
+
+/// The number of sub-second digits of RFC 3339 timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SubsecondPrecision {
+    /// As many digits as needed to represent the timestamp, up to nanoseconds,
+    /// omitting trailing zeros.
+    Auto,
+    /// No sub-second digits.
+    Seconds,
+    /// Exactly 3 digits.
+    Millis,
+    /// Exactly 6 digits.
+    Micros,
+    /// Exactly 9 digits.
+    Nanos,
+}
+
+/// The offset from UTC RFC 3339 timestamps are expressed in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TimestampOffset {
+    Utc,
+    /// The local offset of the machine, determined once when the layer is built.
+    Local,
+    Fixed(UtcOffset),
+}
+
+/// The unit of Unix epoch timestamps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EpochUnit {
+    Seconds,
+    Millis,
+    Micros,
+    Nanos,
+}
+
+/// A `TimestampFormat` whose offset has been resolved.
+pub(crate) struct Timestamper {
+    format: TimestampFormat,
+    offset: UtcOffset,
+}
+
+impl Timestamper {
+    /// Returns `None` if the format requires the local offset and it cannot be determined.
+    pub(crate) fn new(format: TimestampFormat) -> Option<Self> {
+        let offset = match format {
+            TimestampFormat::Rfc3339 {
+                offset: TimestampOffset::Local,
+                ..
+            } => UtcOffset::current_local_offset().ok()?,
+            TimestampFormat::Rfc3339 {
+                offset: TimestampOffset::Fixed(offset),
+                ..
+            } => offset,
+            _ => UtcOffset::UTC,
+        };
+        Some(Self { format, offset })
+    }
+
+    /// Get the value of the `time` field for `now`.
+    ///
+    /// `time` is a mandatory Bunyan field: this never fails.
+    pub(crate) fn timestamp(&self, now: OffsetDateTime) -> Timestamp {
+        match self.format {
+            TimestampFormat::Rfc3339 { precision, .. } => {
+                Timestamp::Rfc3339(now.to_offset(self.offset), precision)
+            }
+            TimestampFormat::UnixEpoch(unit) => {
+                let nanos = now.unix_timestamp_nanos();
+                let elapsed = match unit {
+                    EpochUnit::Seconds => nanos / 1_000_000_000,
+                    EpochUnit::Millis => nanos / 1_000_000,
+                    EpochUnit::Micros => nanos / 1_000,
+                    EpochUnit::Nanos => nanos,
+                };
+                // Nanoseconds since the epoch fit in an i64 until 2262.
+                Timestamp::UnixEpoch(i64::try_from(elapsed).unwrap_or(i64::MAX))
+            }
+        }
+    }
+}
+
+/// The value of the `time` field of a record.
+///
+/// RFC 3339 date-times are written straight to the serializer, without going through
+/// an intermediate `String`.
+pub(crate) enum Timestamp {
+    Rfc3339(OffsetDateTime, SubsecondPrecision),
+    UnixEpoch(i64),
+}
+
+impl Serialize for Timestamp {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Timestamp::Rfc3339(..) => serializer.collect_str(self),
+            Timestamp::UnixEpoch(elapsed) => serializer.serialize_i64(*elapsed),
+        }
+    }
+}
+
+impl fmt::Display for Timestamp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Timestamp::Rfc3339(now, precision) => write_rfc3339(f, *now, *precision),
+            Timestamp::UnixEpoch(elapsed) => write!(f, "{}", elapsed),
+        }
+    }
+}
+
+/// Format a date-time according to RFC 3339.
+///
+/// `SubsecondPrecision::Auto` matches the output of `time`'s `Rfc3339` format description,
+/// but we also handle what it refuses to format: offsets with a seconds component (which are
+/// truncated) and years beyond 9999.
+fn write_rfc3339(
+    f: &mut impl fmt::Write,
+    now: OffsetDateTime,
+    precision: SubsecondPrecision,
+) -> fmt::Result {
+    write!(
+        f,
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+        now.year(),
+        u8::from(now.month()),
+        now.day(),
+        now.hour(),
+        now.minute(),
+        now.second()
+    )?;
+    match precision {
+        SubsecondPrecision::Seconds => {}
+        SubsecondPrecision::Millis => write!(f, ".{:03}", now.millisecond())?,
+        SubsecondPrecision::Micros => write!(f, ".{:06}", now.microsecond())?,
+        SubsecondPrecision::Nanos => write!(f, ".{:09}", now.nanosecond())?,
+        SubsecondPrecision::Auto => {
+            let mut nanos = now.nanosecond();
+            if nanos != 0 {
+                let mut digits = 9;
+                while nanos.is_multiple_of(10) {
+                    nanos /= 10;
+                    digits -= 1;
+                }
+                write!(f, ".{:0width$}", nanos, width = digits)?;
+            }
+        }
+    }
+    let offset = now.offset();
+    if offset.is_utc() {
+        f.write_char('Z')
+    } else {
+        let sign = if offset.is_negative() { '-' } else { '+' };
+        write!(
+            f,
+            "{}{:02}:{:02}",
+            sign,
+            offset.whole_hours().unsigned_abs(),
+            offset.minutes_past_hour().unsigned_abs()
+        )
+    }
+}
@@ -0,0 +1,292 @@
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use tracing_subscriber::fmt::MakeWriter;
+
+/// An object-safe stand-in for [`MakeWriter`], to store writers of different types behind a
+/// `Box` (e.g. the fallback writer of [`BunyanFormattingLayer`](crate::BunyanFormattingLayer)).
+pub(crate) trait RecordWriter: Send + Sync + 'static {
+    /// Write a complete serialised record in one go.
+    fn write_record(&self, record: &[u8]) -> Result<(), std::io::Error>;
+}
+
+impl<W> RecordWriter for W
+where
+    W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
+{
+    fn write_record(&self, record: &[u8]) -> Result<(), std::io::Error> {
+        self.make_writer().write_all(record)
+    }
+}
+
+/// What [`NonBlocking`] does with a new record when its queue is full.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Wait for the worker thread to make room in the queue.
+    Block,
+    /// Drop the new record.
+    #[default]
+    DropNewest,
+    /// Drop the oldest record in the queue to make room for the new one.
+    DropOldest,
+}
+
+/// A [`MakeWriter`] pushing records to a bounded in-memory queue, which is drained by a worker
+/// thread writing them to the wrapped [`MakeWriter`].
+///
+/// Use it as the writer of a [`BunyanFormattingLayer`](crate::BunyanFormattingLayer) to take
+/// slow writes (e.g. to a piped stdout) off the threads emitting records:
+/// ```rust
+/// use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer, NonBlocking};
+/// use tracing_subscriber::prelude::*;
+///
+/// let (non_blocking, _guard) = NonBlocking::new(std::io::stdout);
+/// let formatting_layer = BunyanFormattingLayer::new("tracing_example".into(), non_blocking);
+/// let subscriber = tracing_subscriber::registry()
+///     .with(JsonStorageLayer::default())
+///     .with(formatting_layer);
+/// ```
+///
+/// Records still in the queue are written when the [`WorkerGuard`] is dropped: keep it alive
+/// until the end of `main`.
+#[derive(Clone)]
+pub struct NonBlocking {
+    queue: Arc<Queue>,
+}
+
+impl NonBlocking {
+    /// Spawn a worker thread writing records to `make_writer`, with the default configuration.
+    pub fn new<W>(make_writer: W) -> (Self, WorkerGuard)
+    where
+        W: for<'a> MakeWriter<'a> + Send + 'static,
+    {
+        Self::builder().finish(make_writer)
+    }
+
+    /// Start configuring a `NonBlocking` writer via a [`NonBlockingBuilder`].
+    pub fn builder() -> NonBlockingBuilder {
+        NonBlockingBuilder::default()
+    }
+
+    /// The number of records dropped because the queue was full (or because they were
+    /// emitted after the [`WorkerGuard`] has been dropped).
+    pub fn dropped_records(&self) -> u64 {
+        self.queue.dropped_records.load(Ordering::Relaxed)
+    }
+
+    /// The number of records the worker thread failed to write.
+    pub fn write_errors(&self) -> u64 {
+        self.queue.write_errors.load(Ordering::Relaxed)
+    }
+}
+
+impl<'a> MakeWriter<'a> for NonBlocking {
+    type Writer = NonBlockingWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        NonBlockingWriter {
+            queue: &self.queue,
+            record: Vec::new(),
+        }
+    }
+}
+
+/// Collects the configuration options of a [`NonBlocking`] writer.
+///
+/// Use [`NonBlocking::builder`] to get one.
+#[derive(Clone, Debug)]
+pub struct NonBlockingBuilder {
+    buffered_records: usize,
+    overflow_policy: OverflowPolicy,
+}
+
+impl Default for NonBlockingBuilder {
+    fn default() -> Self {
+        Self {
+            buffered_records: DEFAULT_BUFFERED_RECORDS,
+            overflow_policy: OverflowPolicy::default(),
+        }
+    }
+}
+
+impl NonBlockingBuilder {
+    /// The maximum number of records waiting to be written, not counting the one the worker
+    /// thread is writing.
+    ///
+    /// Defaults to 128,000. It cannot be lower than 1.
+    pub fn buffered_records(mut self, buffered_records: usize) -> Self {
+        self.buffered_records = buffered_records.max(1);
+        self
+    }
+
+    /// Choose what happens to new records when the queue is full.
+    ///
+    /// Defaults to [`OverflowPolicy::DropNewest`].
+    pub fn overflow_policy(mut self, overflow_policy: OverflowPolicy) -> Self {
+        self.overflow_policy = overflow_policy;
+        self
+    }
+
+    /// Spawn the worker thread writing records to `make_writer`.
+    pub fn finish<W>(self, make_writer: W) -> (NonBlocking, WorkerGuard)
+    where
+        W: for<'a> MakeWriter<'a> + Send + 'static,
+    {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState::default()),
+            not_empty: Condvar::new(),
+            not_full: Condvar::new(),
+            capacity: self.buffered_records,
+            overflow_policy: self.overflow_policy,
+            dropped_records: AtomicU64::new(0),
+            write_errors: AtomicU64::new(0),
+        });
+        let worker = {
+            let queue = Arc::clone(&queue);
+            thread::Builder::new()
+                .name("tracing-bunyan-formatter".into())
+                .spawn(move || queue.drain_to(make_writer))
+                .expect("Failed to spawn the worker thread of the non-blocking writer")
+        };
+        let guard = WorkerGuard {
+            queue: Arc::clone(&queue),
+            worker: Some(worker),
+        };
+        (NonBlocking { queue }, guard)
+    }
+}
+
+/// Flushes the records queued by a [`NonBlocking`] writer and stops its worker thread when
+/// dropped.
+#[must_use = "records are lost once the guard is dropped"]
+pub struct WorkerGuard {
+    queue: Arc<Queue>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Drop for WorkerGuard {
+    fn drop(&mut self) {
+        self.queue.lock().shutdown = true;
+        self.queue.not_empty.notify_all();
+        self.queue.not_full.notify_all();
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// The writer handed out by [`NonBlocking`].
+///
+/// It buffers everything written to it and queues it as a single record when dropped
+/// or flushed.
+pub struct NonBlockingWriter<'a> {
+    queue: &'a Queue,
+    record: Vec<u8>,
+}
+
+impl Write for NonBlockingWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.record.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.record.is_empty() {
+            self.queue.push(std::mem::take(&mut self.record));
+        }
+        Ok(())
+    }
+}
+
+impl Drop for NonBlockingWriter<'_> {
+    fn drop(&mut self) {
+        let _ = self.flush();
+    }
+}
+
+const DEFAULT_BUFFERED_RECORDS: usize = 128_000;
+
+struct Queue {
+    state: Mutex<QueueState>,
+    not_empty: Condvar,
+    not_full: Condvar,
+    capacity: usize,
+    overflow_policy: OverflowPolicy,
+    dropped_records: AtomicU64,
+    write_errors: AtomicU64,
+}
+
+#[derive(Default)]
+struct QueueState {
+    records: VecDeque<Vec<u8>>,
+    shutdown: bool,
+}
+
+impl Queue {
+    // A panic while holding the lock cannot leave the queue in an inconsistent state.
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn push(&self, record: Vec<u8>) {
+        let mut state = self.lock();
+        while state.records.len() >= self.capacity && !state.shutdown {
+            match self.overflow_policy {
+                OverflowPolicy::Block => {
+                    state = self
+                        .not_full
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                OverflowPolicy::DropNewest => {
+                    self.dropped_records.fetch_add(1, Ordering::Relaxed);
+                    return;
+                }
+                OverflowPolicy::DropOldest => {
+                    state.records.pop_front();
+                    self.dropped_records.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        }
+        if state.shutdown {
+            self.dropped_records.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        state.records.push_back(record);
+        self.not_empty.notify_one();
+    }
+
+    /// Write queued records to `make_writer` until the queue is shut down and empty.
+    ///
+    /// Records are taken off the queue one at a time, so that the queue and the record being
+    /// written never hold more than `capacity + 1` records, and `DropOldest` can drop any record
+    /// that is still waiting.
+    fn drain_to<W: for<'a> MakeWriter<'a>>(&self, make_writer: W) {
+        loop {
+            let record = {
+                let mut state = self.lock();
+                while state.records.is_empty() && !state.shutdown {
+                    state = self
+                        .not_empty
+                        .wait(state)
+                        .unwrap_or_else(PoisonError::into_inner);
+                }
+                match state.records.pop_front() {
+                    Some(record) => record,
+                    None => return,
+                }
+            };
+            self.not_full.notify_one();
+            let mut writer = make_writer.make_writer();
+            if writer
+                .write_all(&record)
+                .and_then(|()| writer.flush())
+                .is_err()
+            {
+                self.write_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
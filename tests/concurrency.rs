@@ -0,0 +1,142 @@
+use crate::mock_writer::MockMakeWriter;
+use serde_json::Value;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+use tracing::{info, span, Dispatch, Level};
+use tracing_bunyan_formatter::{
+    BunyanFormattingLayer, Clock, EnterExitRecords, JsonStorageLayer, SpanRecords,
+};
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+// Not every test binary uses every helper.
+#[allow(dead_code)]
+mod mock_writer;
+
+const ITERATIONS: usize = 20_000;
+
+// The extensions of a span are behind a `RwLock` letting waiting writers go first: formatting a
+// record must not lock them twice, or it deadlocks with a thread recording fields on the span.
+#[test]
+fn formatting_records_does_not_deadlock_with_concurrent_span_updates() {
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), std::io::sink)
+        .span_ids(true)
+        .live_inherited_fields(true)
+        .enter_exit_records(EnterExitRecords::All)
+        .build()
+        .unwrap();
+    let dispatch = Dispatch::new(
+        Registry::default()
+            .with(JsonStorageLayer::default())
+            .with(formatting_layer),
+    );
+    let (done, finished) = mpsc::channel();
+
+    let span = tracing::dispatcher::with_default(&dispatch, || {
+        let parent = span!(Level::INFO, "parent", user_id = tracing::field::Empty);
+        span!(parent: &parent, Level::INFO, "child", counter = 0)
+    });
+    let threads: Vec<_> = (0..2)
+        .map(|worker| {
+            let dispatch = dispatch.clone();
+            let span = span.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                tracing::dispatcher::with_default(&dispatch, || {
+                    for i in 0..ITERATIONS {
+                        if worker == 0 {
+                            span.in_scope(|| info!("working"));
+                        } else {
+                            span.record("counter", i);
+                            span.in_scope(|| ());
+                        }
+                    }
+                });
+                done.send(()).unwrap();
+            })
+        })
+        .collect();
+
+    for _ in &threads {
+        finished
+            .recv_timeout(Duration::from_secs(60))
+            .expect("The threads are deadlocked");
+    }
+    for thread in threads {
+        thread.join().unwrap();
+    }
+}
+
+/// A clock which never moves forward, so that rate limits never reset during a test.
+struct FrozenClock(Instant);
+
+impl Clock for FrozenClock {
+    fn now(&self) -> time::OffsetDateTime {
+        time::OffsetDateTime::UNIX_EPOCH
+    }
+
+    fn instant(&self) -> Instant {
+        self.0
+    }
+}
+
+#[test]
+fn rate_limited_exit_records_match_the_enter_records_of_their_thread() {
+    let make_writer = MockMakeWriter::new();
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), make_writer.clone())
+        .clock(FrozenClock(Instant::now()))
+        .span_records(SpanRecords::None)
+        .enter_exit_records(EnterExitRecords::RateLimited { max_per_second: 1 })
+        .build()
+        .unwrap();
+    let dispatch = Dispatch::new(
+        Registry::default()
+            .with(JsonStorageLayer::default())
+            .with(formatting_layer),
+    );
+    let span = tracing::dispatcher::with_default(&dispatch, || span!(Level::INFO, "shared"));
+    let (first_sender, first_receiver) = mpsc::channel();
+    let (second_sender, second_receiver) = mpsc::channel();
+
+    // The first thread enters the span (ENTER emitted), then the second one (ENTER skipped),
+    // then the first thread exits it before the second one.
+    let first = {
+        let dispatch = dispatch.clone();
+        let span = span.clone();
+        thread::spawn(move || {
+            tracing::dispatcher::with_default(&dispatch, || {
+                let entered = span.enter();
+                first_sender.send(()).unwrap();
+                second_receiver.recv().unwrap();
+                drop(entered);
+                info!("first thread exited");
+                first_sender.send(()).unwrap();
+            })
+        })
+    };
+    let second = thread::spawn(move || {
+        tracing::dispatcher::with_default(&dispatch, || {
+            first_receiver.recv().unwrap();
+            let entered = span.enter();
+            second_sender.send(()).unwrap();
+            first_receiver.recv().unwrap();
+            drop(entered);
+        })
+    });
+    first.join().unwrap();
+    second.join().unwrap();
+
+    let messages: Vec<String> = make_writer
+        .get_string()
+        .lines()
+        .map(|line| {
+            let record: Value = serde_json::from_str(line).unwrap();
+            record["msg"].as_str().unwrap().to_owned()
+        })
+        .collect();
+    assert_eq!(
+        messages,
+        ["[SHARED - ENTER]", "[SHARED - EXIT]", "first thread exited"]
+    );
+}
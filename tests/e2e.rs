@@ -1,13 +1,18 @@
-use crate::mock_writer::MockWriter;
+use crate::mock_writer::{FailingMakeWriter, MockMakeWriter, MockWriter};
 use lazy_static::lazy_static;
 use serde_json::{json, Value};
 use std::collections::HashMap;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use time::format_description::well_known::Rfc3339;
 use tracing::{info, span, Level};
-use tracing_bunyan_formatter::{BunyanFormattingLayer, JsonStorageLayer};
-use tracing_subscriber::layer::SubscriberExt;
+use tracing_bunyan_formatter::{
+    BuildError, BunyanFormattingLayer, BunyanFormattingLayerBuilder, BunyanLevel, Clock,
+    Diagnostic, DiagnosticWriter, DurationField, DurationUnit, EnterExitRecords, EpochUnit,
+    FieldCollisionPolicy, FieldInheritance, FieldOrder, FieldSource, JsonStorage, JsonStorageLayer,
+    SpanRecords, SpanTimings, SubsecondPrecision, TimestampFormat, TimestampOffset, Type,
+};
+use tracing_subscriber::layer::{Layer, SubscriberExt};
 use tracing_subscriber::Registry;
-use time::format_description::well_known::Rfc3339;
 
 mod mock_writer;
 
@@ -22,9 +27,13 @@ lazy_static! {
 fn run_and_get_raw_output<F: Fn()>(action: F) -> String {
     let mut default_fields = HashMap::new();
     default_fields.insert("custom_field".to_string(), json!("custom_value"));
-    let formatting_layer = BunyanFormattingLayer::with_default_fields("test".into(), || MockWriter::new(&BUFFER), default_fields);
+    let formatting_layer = BunyanFormattingLayer::with_default_fields(
+        "test".into(),
+        || MockWriter::new(&BUFFER),
+        default_fields,
+    );
     let subscriber = Registry::default()
-        .with(JsonStorageLayer)
+        .with(JsonStorageLayer::default())
         .with(formatting_layer);
     tracing::subscriber::with_default(subscriber, action);
 
@@ -47,6 +56,49 @@ fn run_and_get_output<F: Fn()>(action: F) -> Vec<Value> {
         .collect()
 }
 
+// Run a closure and collect the output emitted by a `BunyanFormattingLayer` customised via
+// `configure`, on top of `storage_layer` (a `JsonStorageLayer`, possibly followed by
+// enrichment layers), as structured new-line-delimited JSON.
+// Each invocation gets its own in-memory buffer, hence it is safe to use concurrently.
+fn run_with_builder_and_get_output<L, C, F>(storage_layer: L, configure: C, action: F) -> Vec<Value>
+where
+    L: Layer<Registry> + Send + Sync,
+    C: FnOnce(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+    F: Fn(),
+{
+    run_with_builder_and_get_raw_output(storage_layer, configure, action)
+        .lines()
+        .filter(|&l| !l.is_empty())
+        .inspect(|l| println!("{}", l))
+        .map(|line| serde_json::from_str::<Value>(line).unwrap())
+        .collect()
+}
+
+// Like `run_with_builder_and_get_output`, returning the raw output.
+fn run_with_builder_and_get_raw_output<L, C, F>(storage_layer: L, configure: C, action: F) -> String
+where
+    L: Layer<Registry> + Send + Sync,
+    C: FnOnce(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>,
+    F: Fn(),
+{
+    let make_writer = MockMakeWriter::new();
+    let formatting_layer = configure(BunyanFormattingLayer::builder(
+        "test".into(),
+        make_writer.clone(),
+    ))
+    .build()
+    .unwrap();
+    let subscriber = Registry::default()
+        .with(storage_layer)
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, action);
+    make_writer.get_string()
+}
+
 // Instrumented code to be run to test the behaviour of the tracing instrumentation.
 fn test_action() {
     let a = 2;
@@ -126,9 +178,1272 @@ fn elapsed_milliseconds_are_present_on_exit_span() {
         if record
             .get("msg")
             .and_then(Value::as_str)
-            .map_or(false, |msg| msg.ends_with("END]"))
+            .is_some_and(|msg| msg.ends_with("END]"))
         {
             assert!(record.get("elapsed_milliseconds").is_some());
         }
     }
 }
+
+#[test]
+fn builder_overrides_core_fields_and_attaches_default_fields() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder
+                .hostname("a-host")
+                .pid(42)
+                .default_field("custom_field", json!("custom_value"))
+        },
+        test_action,
+    );
+
+    assert!(!tracing_output.is_empty());
+    for record in tracing_output {
+        assert_eq!(record["hostname"], json!("a-host"));
+        assert_eq!(record["pid"], json!(42));
+        assert_eq!(record["custom_field"], json!("custom_value"));
+    }
+}
+
+#[test]
+fn builder_rejects_invalid_configurations() {
+    let build = |configure: fn(
+        BunyanFormattingLayerBuilder<MockMakeWriter>,
+    ) -> BunyanFormattingLayerBuilder<MockMakeWriter>| {
+        configure(BunyanFormattingLayer::builder(
+            "test".into(),
+            MockMakeWriter::new(),
+        ))
+        .build()
+        .err()
+    };
+
+    assert_eq!(
+        build(|builder| builder.default_field("time", json!("yesterday"))),
+        Some(BuildError::ReservedField("time".into()))
+    );
+    assert_eq!(
+        build(|builder| builder.hostname("")),
+        Some(BuildError::EmptyHostname)
+    );
+    assert_eq!(build(|builder| builder), None);
+    assert_eq!(
+        BunyanFormattingLayer::builder("".into(), MockMakeWriter::new())
+            .build()
+            .err(),
+        Some(BuildError::EmptyName)
+    );
+}
+
+#[test]
+fn source_location_can_be_nested_under_src() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.nested_src(true),
+        || {
+            test_action();
+            info!(target: "audit", "custom target");
+        },
+    );
+
+    assert!(!tracing_output.is_empty());
+    let (custom, default) = tracing_output.split_last().unwrap();
+    assert_eq!(custom["target"], json!("audit"));
+    for record in default {
+        assert_eq!(record["target"], json!(module_path!()));
+    }
+    for record in tracing_output.iter() {
+        assert!(record.get("line").is_none());
+        assert!(record.get("file").is_none());
+
+        let src = record.get("src").unwrap();
+        assert_eq!(src["file"], json!(file!()));
+        assert!(src["line"].is_u64());
+        assert_eq!(src["func"], json!(module_path!()));
+    }
+}
+
+#[test]
+fn src_is_reserved_when_nested() {
+    let result = BunyanFormattingLayer::builder("test".into(), MockMakeWriter::new())
+        .nested_src(true)
+        .default_field("src", json!("somewhere"))
+        .build();
+
+    assert_eq!(result.err(), Some(BuildError::ReservedField("src".into())));
+}
+
+#[derive(Debug)]
+struct TestError {
+    message: &'static str,
+    source: Option<Box<TestError>>,
+}
+
+impl std::fmt::Display for TestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for TestError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.source
+            .as_deref()
+            .map(|e| e as &(dyn std::error::Error + 'static))
+    }
+}
+
+#[test]
+fn floats_wide_integers_and_errors_are_recorded_as_structured_values() {
+    let action = || {
+        let error = TestError {
+            message: "failed to load configuration",
+            source: Some(Box::new(TestError {
+                message: "file not found",
+                source: None,
+            })),
+        };
+        info!(
+            ratio = 0.5_f64,
+            not_a_number = f64::NAN,
+            infinite = f64::INFINITY,
+            small = 42_u128,
+            huge = u128::MAX,
+            negative = i128::MIN,
+            error = &error as &(dyn std::error::Error + 'static),
+            "wide values"
+        );
+    };
+    let tracing_output =
+        run_with_builder_and_get_output(JsonStorageLayer::default(), |builder| builder, action);
+    let record = &tracing_output[0];
+
+    assert_eq!(record["ratio"], json!(0.5));
+    assert_eq!(record["not_a_number"], json!("NaN"));
+    assert_eq!(record["infinite"], json!("inf"));
+    assert_eq!(record["small"], json!(42));
+    #[cfg(not(feature = "arbitrary-precision"))]
+    {
+        assert_eq!(record["huge"], json!(u128::MAX.to_string()));
+        assert_eq!(record["negative"], json!(i128::MIN.to_string()));
+    }
+    #[cfg(feature = "arbitrary-precision")]
+    {
+        assert_eq!(record["huge"].to_string(), u128::MAX.to_string());
+        assert_eq!(record["negative"].to_string(), i128::MIN.to_string());
+    }
+    let error = &record["error"];
+    assert_eq!(error["message"], json!("failed to load configuration"));
+    assert!(error.get("name").is_none());
+    assert_eq!(error["sources"], json!(["file not found"]));
+    // No backtrace unless explicitly enabled, whatever `RUST_BACKTRACE` says.
+    assert_eq!(
+        error["stack"],
+        json!("failed to load configuration\nCaused by: file not found")
+    );
+}
+
+/// An error printing its message as its `Debug` representation, like `anyhow::Error` does.
+struct OpaqueError;
+
+impl std::fmt::Debug for OpaqueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection refused by upstream")
+    }
+}
+
+impl std::fmt::Display for OpaqueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "connection refused by upstream")
+    }
+}
+
+impl std::error::Error for OpaqueError {}
+
+/// An error enum, whose `Debug` representation starts with the name of the variant.
+#[derive(Debug)]
+enum AppError {
+    NotFound(String),
+    Timeout { secs: u64 },
+}
+
+impl std::fmt::Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::NotFound(what) => write!(f, "{} not found", what),
+            AppError::Timeout { secs } => write!(f, "timed out after {}s", secs),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+#[test]
+fn error_names_are_left_out_unless_they_are_known() {
+    let action = || {
+        info!(
+            err = &OpaqueError as &(dyn std::error::Error + 'static),
+            "opaque failure"
+        );
+        let not_found = AppError::NotFound("user".into());
+        info!(
+            err = &not_found as &(dyn std::error::Error + 'static),
+            "enum failure"
+        );
+        let timeout = AppError::Timeout { secs: 5 };
+        info!(
+            err = &timeout as &(dyn std::error::Error + 'static),
+            "enum failure"
+        );
+    };
+    let tracing_output =
+        run_with_builder_and_get_output(JsonStorageLayer::default(), |builder| builder, action);
+
+    let messages = [
+        "connection refused by upstream",
+        "user not found",
+        "timed out after 5s",
+    ];
+    for (record, message) in tracing_output.iter().zip(messages) {
+        let err = &record["err"];
+        assert_eq!(err["message"], json!(message));
+        assert!(err.get("name").is_none());
+    }
+}
+
+#[test]
+fn io_errors_carry_their_kind_as_code() {
+    let action = || {
+        let error = std::io::Error::new(std::io::ErrorKind::NotFound, "no such file");
+        info!(
+            err = &error as &(dyn std::error::Error + 'static),
+            "io failure"
+        );
+    };
+    let tracing_output =
+        run_with_builder_and_get_output(JsonStorageLayer::default(), |builder| builder, action);
+    let err = &tracing_output[0]["err"];
+
+    assert_eq!(err["message"], json!("no such file"));
+    assert_eq!(err["name"], json!("std::io::Error"));
+    assert_eq!(err["code"], json!("NotFound"));
+    assert!(err["stack"].as_str().unwrap().starts_with("no such file"));
+}
+
+#[test]
+fn span_ids_correlate_records() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_ids(true),
+        test_action,
+    );
+
+    // START of the outer span, event, START of the inner span, event, END of the inner span,
+    // END of the outer span.
+    assert_eq!(tracing_output.len(), 6);
+    let outer_span_id = tracing_output[0]["span_id"].clone();
+    let inner_span_id = tracing_output[2]["span_id"].clone();
+    assert!(outer_span_id.is_u64());
+    assert_ne!(outer_span_id, inner_span_id);
+
+    for record in &tracing_output {
+        assert_eq!(record["trace_id"], outer_span_id);
+    }
+    for record in [&tracing_output[0], &tracing_output[1], &tracing_output[5]] {
+        assert_eq!(record["span_id"], outer_span_id);
+        assert!(record.get("parent_span_id").is_none());
+    }
+    for record in [&tracing_output[2], &tracing_output[3], &tracing_output[4]] {
+        assert_eq!(record["span_id"], inner_span_id);
+        assert_eq!(record["parent_span_id"], outer_span_id);
+    }
+}
+
+#[test]
+fn span_ids_are_not_emitted_by_default() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder,
+        test_action,
+    );
+
+    for record in tracing_output {
+        assert!(record.get("span_id").is_none());
+        assert!(record.get("parent_span_id").is_none());
+        assert!(record.get("trace_id").is_none());
+    }
+}
+
+#[test]
+fn levels_can_be_remapped_and_named() {
+    let action = || {
+        info!("business as usual");
+        tracing::error!(fatal = true, "the end is near");
+        tracing::warn!(target: "doom", "impending");
+    };
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder
+                .level_mapper(
+                    |metadata: &tracing::Metadata<'_>, fields: &JsonStorage<'_>| {
+                        if fields.get("fatal") == Some(&json!(true)) || metadata.target() == "doom"
+                        {
+                            BunyanLevel::Fatal
+                        } else {
+                            BunyanLevel::from(metadata.level())
+                        }
+                    },
+                )
+                .level_name(true)
+        },
+        action,
+    );
+
+    assert_eq!(tracing_output[0]["level"], json!(30));
+    assert_eq!(tracing_output[0]["level_name"], json!("info"));
+    assert_eq!(tracing_output[1]["level"], json!(60));
+    assert_eq!(tracing_output[1]["level_name"], json!("fatal"));
+    assert_eq!(tracing_output[2]["level"], json!(60));
+}
+
+#[test]
+fn levels_follow_tracing_levels_by_default() {
+    let action = || {
+        tracing::trace!("trace");
+        tracing::debug!("debug");
+        info!("info");
+        tracing::warn!("warn");
+        tracing::error!("error");
+    };
+    let tracing_output =
+        run_with_builder_and_get_output(JsonStorageLayer::default(), |builder| builder, action);
+    let levels: Vec<_> = tracing_output.iter().map(|r| r["level"].clone()).collect();
+
+    assert_eq!(
+        levels,
+        vec![json!(10), json!(20), json!(30), json!(40), json!(50)]
+    );
+    assert!(tracing_output[0].get("level_name").is_none());
+}
+
+#[test]
+fn time_can_have_a_fixed_precision_and_offset() {
+    let offset = time::UtcOffset::from_hms(-3, -30, 0).unwrap();
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder.timestamp_format(TimestampFormat::Rfc3339 {
+                precision: SubsecondPrecision::Micros,
+                offset: TimestampOffset::Fixed(offset),
+            })
+        },
+        test_action,
+    );
+
+    for record in tracing_output {
+        let time = record["time"].as_str().unwrap();
+        // e.g. 2020-05-02T14:31:01.123456-03:30
+        assert_eq!(time.len(), 32, "{}", time);
+        assert!(time.ends_with("-03:30"));
+        let parsed = time::OffsetDateTime::parse(time, &Rfc3339).unwrap();
+        assert_eq!(parsed.offset(), offset);
+    }
+}
+
+#[test]
+fn time_can_be_a_unix_epoch_timestamp() {
+    let before = time::OffsetDateTime::now_utc().unix_timestamp() * 1000;
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.timestamp_format(TimestampFormat::UnixEpoch(EpochUnit::Millis)),
+        test_action,
+    );
+    let after = time::OffsetDateTime::now_utc().unix_timestamp() * 1000 + 1000;
+
+    for record in tracing_output {
+        let time = record["time"].as_i64().unwrap();
+        assert!(before <= time && time <= after);
+    }
+}
+
+/// A clock that starts at the Unix epoch and moves forward by one millisecond every time
+/// it is queried.
+struct TickingClock {
+    origin: std::time::Instant,
+    ticks: std::sync::atomic::AtomicU64,
+}
+
+impl TickingClock {
+    fn new() -> Arc<Self> {
+        Arc::new(Self {
+            origin: std::time::Instant::now(),
+            ticks: Default::default(),
+        })
+    }
+
+    fn tick(&self) -> std::time::Duration {
+        let ticks = self.ticks.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        std::time::Duration::from_millis(ticks)
+    }
+}
+
+impl Clock for TickingClock {
+    fn now(&self) -> time::OffsetDateTime {
+        time::OffsetDateTime::UNIX_EPOCH + self.tick()
+    }
+
+    fn instant(&self) -> std::time::Instant {
+        self.origin + self.tick()
+    }
+}
+
+#[test]
+fn a_fake_clock_makes_records_reproducible() {
+    let run = || {
+        let clock = TickingClock::new();
+        run_with_builder_and_get_raw_output(
+            JsonStorageLayer::default().with_clock(clock.clone()),
+            |builder| builder.hostname("a-host").pid(42).clock(clock),
+            test_action,
+        )
+    };
+
+    let output = run();
+    // Byte for byte, fields included.
+    assert_eq!(output, run());
+    let records: Vec<Value> = output
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records[0]["time"], json!("1970-01-01T00:00:00Z"));
+    for record in &records {
+        let time = record["time"].as_str().unwrap();
+        let parsed = time::OffsetDateTime::parse(time, &Rfc3339).unwrap();
+        assert_eq!(parsed.format(&Rfc3339).unwrap(), time);
+    }
+    assert_eq!(records[4]["elapsed_milliseconds"], json!(2));
+    assert_eq!(records[5]["elapsed_milliseconds"], json!(7));
+}
+
+fn messages(records: &[Value]) -> Vec<&str> {
+    records
+        .iter()
+        .map(|record| record["msg"].as_str().unwrap())
+        .collect()
+}
+
+#[test]
+fn span_records_can_be_limited_to_end_records_or_suppressed() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_records(SpanRecords::EndOnly),
+        test_action,
+    );
+    assert_eq!(
+        messages(&tracing_output),
+        vec![
+            "[SHAVING_YAKS - EVENT] pre-shaving yaks",
+            "[INNER SHAVING - EVENT] shaving yaks",
+            "[INNER SHAVING - END]",
+            "[SHAVING_YAKS - END]",
+        ]
+    );
+
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_records(SpanRecords::None),
+        test_action,
+    );
+    assert_eq!(
+        messages(&tracing_output),
+        vec![
+            "[SHAVING_YAKS - EVENT] pre-shaving yaks",
+            "[INNER SHAVING - EVENT] shaving yaks",
+        ]
+    );
+    // Span fields are still attached to events.
+    assert_eq!(tracing_output[1]["a"], json!(2));
+    assert_eq!(tracing_output[1]["b"], json!(3));
+}
+
+#[test]
+fn span_records_can_be_filtered_span_by_span() {
+    let action = || {
+        let span = span!(Level::INFO, "noisy", log_span = false, a = 1);
+        let _enter = span.enter();
+        let inner_span = span!(Level::INFO, "interesting", log_span = true);
+        let _enter_inner = inner_span.enter();
+        info!("shaving yaks");
+    };
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder.span_record_filter(
+                |_metadata: &tracing::Metadata<'_>, fields: &JsonStorage<'_>, _ty: &Type| {
+                    fields.get("log_span") != Some(&json!(false))
+                },
+            )
+        },
+        action,
+    );
+
+    assert_eq!(
+        messages(&tracing_output),
+        vec![
+            "[INTERESTING - START]",
+            "[INTERESTING - EVENT] shaving yaks",
+            "[INTERESTING - END]",
+        ]
+    );
+    assert_eq!(tracing_output[1]["a"], json!(1));
+}
+
+#[test]
+fn messages_can_be_customised() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder.message_formatter(
+                |span: Option<&tracing::Metadata<'_>>, ty: &Type, message: Option<&str>| match (
+                    span, message,
+                ) {
+                    (_, Some(message)) => message.to_owned(),
+                    (Some(span), None) => format!("{} {}", span.name(), ty),
+                    (None, None) => unreachable!(),
+                },
+            )
+        },
+        test_action,
+    );
+
+    assert_eq!(
+        messages(&tracing_output),
+        vec![
+            "shaving_yaks START",
+            "pre-shaving yaks",
+            "inner shaving START",
+            "shaving yaks",
+            "inner shaving END",
+            "shaving_yaks END",
+        ]
+    );
+}
+
+#[test]
+fn orphan_events_are_not_prefixed_by_default() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder,
+        || {
+            info!("orphan");
+            tracing::info!(target: "no_message", answer = 42);
+        },
+    );
+
+    assert_eq!(messages(&tracing_output), vec!["orphan", "no_message"]);
+}
+
+#[test]
+fn reserved_fields_can_be_prefixed() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.field_collision_policy(FieldCollisionPolicy::Prefix("fields.".into())),
+        || {
+            let span = span!(Level::INFO, "shaving", name = "yak", pid = 1);
+            let _enter = span.enter();
+            info!(time = "now", hostname = "barber", "shaving yaks");
+        },
+    );
+
+    for record in &tracing_output {
+        assert_eq!(record["name"], "test");
+        assert_eq!(record["fields.name"], "yak");
+        assert_eq!(record["fields.pid"], 1);
+        assert!(record["pid"].is_u64());
+    }
+    let event = &tracing_output[1];
+    assert_eq!(event["fields.time"], "now");
+    assert_eq!(event["fields.hostname"], "barber");
+    assert!(event["time"].is_string());
+    assert_ne!(event["hostname"], "barber");
+}
+
+#[test]
+fn user_fields_can_be_nested() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder
+                .default_field("service", json!("yak-shop"))
+                .field_collision_policy(FieldCollisionPolicy::Nest("fields".into()))
+        },
+        || {
+            let span = span!(Level::INFO, "shaving", name = "yak");
+            let _enter = span.enter();
+            info!(time = "now", "shaving yaks");
+        },
+    );
+
+    for record in &tracing_output {
+        assert_eq!(record["name"], "test");
+        assert_eq!(record["fields"]["name"], "yak");
+        assert_eq!(record["fields"]["service"], "yak-shop");
+        assert!(record.get("service").is_none());
+    }
+    assert_eq!(tracing_output[1]["fields"]["time"], "now");
+    assert!(tracing_output[1]["fields"].get("message").is_none());
+}
+
+#[test]
+fn nesting_key_is_reserved() {
+    let result = BunyanFormattingLayer::builder("test".into(), std::io::sink)
+        .field_collision_policy(FieldCollisionPolicy::Nest("fields".into()))
+        .default_field("fields", json!(1))
+        .build();
+    assert_eq!(
+        result.err(),
+        Some(BuildError::ReservedField("fields".into()))
+    );
+
+    let result = BunyanFormattingLayer::builder("test".into(), std::io::sink)
+        .field_collision_policy(FieldCollisionPolicy::Prefix(String::new()))
+        .build();
+    assert_eq!(result.err(), Some(BuildError::EmptyFieldCollisionKey));
+}
+
+#[test]
+fn dropped_fields_are_reported_without_going_through_tracing() {
+    let diagnostics_output = MockMakeWriter::new();
+    let make_writer = MockMakeWriter::new();
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), make_writer.clone())
+        .diagnostic_handler(DiagnosticWriter::new(diagnostics_output.clone()))
+        .build()
+        .unwrap();
+    let diagnostics = formatting_layer.diagnostics();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer::default())
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || {
+        let span = span!(Level::INFO, "shaving", name = "yak");
+        let _enter = span.enter();
+        info!("shaving yaks");
+    });
+
+    // START, EVENT and END all drop the `name` span field, without any extra record.
+    assert_eq!(make_writer.get_string().lines().count(), 3);
+    assert_eq!(diagnostics.dropped_fields(), 3);
+    assert_eq!(diagnostics.write_errors(), 0);
+    assert_eq!(diagnostics.serialization_errors(), 0);
+    let diagnostics_output = diagnostics_output.get_string();
+    assert_eq!(diagnostics_output.lines().count(), 3);
+    assert!(diagnostics_output
+        .lines()
+        .all(|line| line.contains("name is a reserved field")));
+}
+
+#[test]
+fn write_failures_are_reported() {
+    let reported = Arc::new(Mutex::new(Vec::new()));
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), FailingMakeWriter)
+        .diagnostic_handler({
+            let reported = Arc::clone(&reported);
+            move |diagnostic: &Diagnostic<'_>| {
+                if let Diagnostic::WriteFailed(e) = diagnostic {
+                    reported.lock().unwrap().push(e.kind());
+                }
+            }
+        })
+        .build()
+        .unwrap();
+    let diagnostics = formatting_layer.diagnostics();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer::default())
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || info!("lost"));
+
+    assert_eq!(diagnostics.write_errors(), 1);
+    assert_eq!(diagnostics.dropped_records(), 1);
+    assert_eq!(
+        *reported.lock().unwrap(),
+        vec![std::io::ErrorKind::BrokenPipe]
+    );
+}
+
+#[test]
+fn records_fall_back_to_the_fallback_writer() {
+    let fallback = MockMakeWriter::new();
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), FailingMakeWriter)
+        .fallback_writer(fallback.clone())
+        .build()
+        .unwrap();
+    let diagnostics = formatting_layer.diagnostics();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer::default())
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || info!("rescued"));
+
+    let record: Value = serde_json::from_str(fallback.get_string().trim_end()).unwrap();
+    assert_eq!(record["msg"], "rescued");
+    assert_eq!(diagnostics.write_errors(), 1);
+    assert_eq!(diagnostics.dropped_records(), 0);
+
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), FailingMakeWriter)
+        .fallback_writer(FailingMakeWriter)
+        .build()
+        .unwrap();
+    let diagnostics = formatting_layer.diagnostics();
+    let subscriber = Registry::default()
+        .with(JsonStorageLayer::default())
+        .with(formatting_layer);
+    tracing::subscriber::with_default(subscriber, || info!("lost"));
+
+    assert_eq!(diagnostics.write_errors(), 1);
+    assert_eq!(diagnostics.fallback_write_errors(), 1);
+    assert_eq!(diagnostics.dropped_records(), 1);
+}
+
+#[test]
+fn spans_inherit_the_fields_of_their_ancestors_when_created() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_records(SpanRecords::None),
+        || {
+            let root = span!(
+                Level::INFO,
+                "root",
+                shared = "root",
+                depth = 0,
+                late = tracing::field::Empty
+            );
+            let _root = root.enter();
+            let child = span!(Level::INFO, "child", depth = 1);
+            root.record("late", "too late");
+            let _child = child.enter();
+            let grandchild = span!(Level::INFO, "grandchild", depth = 2);
+            let _grandchild = grandchild.enter();
+            info!("deep");
+            drop(_grandchild);
+            drop(_child);
+            info!("shallow");
+        },
+    );
+
+    let deep = &tracing_output[0];
+    assert_eq!(deep["shared"], "root");
+    assert_eq!(deep["depth"], 2);
+    assert!(deep.get("late").is_none());
+    let shallow = &tracing_output[1];
+    assert_eq!(shallow["depth"], 0);
+    assert_eq!(shallow["late"], "too late");
+}
+
+#[test]
+fn inherited_fields_can_be_resolved_when_formatting() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.live_inherited_fields(true),
+        || {
+            let root = span!(Level::INFO, "root", user_id = tracing::field::Empty);
+            let _root = root.enter();
+            let child = span!(Level::INFO, "child", depth = 1);
+            let _child = child.enter();
+            info!("anonymous");
+            root.record("user_id", 42);
+            info!("authenticated");
+        },
+    );
+
+    assert_eq!(
+        messages(&tracing_output)[2..],
+        [
+            "[CHILD - EVENT] anonymous",
+            "[CHILD - EVENT] authenticated",
+            "[CHILD - END]",
+            "[ROOT - END]"
+        ]
+    );
+    assert!(tracing_output[2].get("user_id").is_none());
+    for record in &tracing_output[3..] {
+        assert_eq!(record["user_id"], 42);
+    }
+}
+
+#[test]
+fn spans_record_busy_and_idle_time() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default()
+            .with_clock(TickingClock::new())
+            .with_span_timings(SpanTimings::all()),
+        |builder| builder.span_records(SpanRecords::EndOnly),
+        || {
+            // Created at 0ms.
+            let span = span!(Level::INFO, "polled");
+            // Busy from 1ms to 2ms, re-entering the span does not query the clock.
+            span.in_scope(|| span.in_scope(|| ()));
+            // Busy from 3ms to 4ms, then closed at 5ms.
+            span.in_scope(|| ());
+        },
+    );
+
+    let record = &tracing_output[0];
+    assert_eq!(record["elapsed_milliseconds"], 4);
+    assert_eq!(record["busy_ns"], 2_000_000);
+    assert_eq!(record["idle_ns"], 3_000_000);
+    assert_eq!(record["enter_count"], 3);
+    assert_eq!(record["wall_ns"], 5_000_000);
+}
+
+#[test]
+fn span_durations_can_have_custom_names_and_units() {
+    let span_timings = SpanTimings::none()
+        .elapsed(DurationField::new("elapsed_us", DurationUnit::Micros))
+        .busy(DurationField::new("busy_seconds", DurationUnit::Seconds))
+        .wall(DurationField::new("duration", DurationUnit::Millis))
+        .enter_count_name("polls");
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default()
+            .with_clock(TickingClock::new())
+            .with_span_timings(span_timings),
+        |builder| builder.span_records(SpanRecords::EndOnly),
+        || {
+            // Same timeline as `spans_record_busy_and_idle_time`.
+            let span = span!(Level::INFO, "polled");
+            span.in_scope(|| ());
+            span.in_scope(|| ());
+        },
+    );
+
+    let record = &tracing_output[0];
+    assert_eq!(record["elapsed_us"], 4_000);
+    assert_eq!(record["busy_seconds"], 0.002);
+    assert_eq!(record["duration"], 5);
+    assert_eq!(record["polls"], 2);
+    assert!(record.get("elapsed_milliseconds").is_none());
+    assert!(record.get("idle_ns").is_none());
+}
+
+#[test]
+fn span_timings_can_be_turned_off() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default().with_span_timings(SpanTimings::none()),
+        |builder| builder.span_records(SpanRecords::EndOnly),
+        || span!(Level::INFO, "untimed").in_scope(|| ()),
+    );
+
+    let record = &tracing_output[0];
+    assert_eq!(record["msg"], "[UNTIMED - END]");
+    assert!(record.get("elapsed_milliseconds").is_none());
+}
+
+// Run `action` and get the raw output, to check the order of the fields.
+fn run_with_field_order(field_order: FieldOrder) -> String {
+    run_with_builder_and_get_raw_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder
+                .default_field("default_b", json!(1))
+                .default_field("default_a", json!(1))
+                .field_order(field_order)
+                .span_records(SpanRecords::None)
+        },
+        || {
+            let outer = span!(Level::INFO, "outer", span_z = 1, span_y = 1);
+            let _outer = outer.enter();
+            let inner = span!(Level::INFO, "inner", span_x = 1, span_z = 2);
+            let _inner = inner.enter();
+            info!(event_b = 1, event_a = 1, "ordered");
+        },
+    )
+}
+
+// The keys of `keys` found in `output`, in the order they appear.
+fn key_order<'a>(output: &str, keys: &[&'a str]) -> Vec<&'a str> {
+    let mut found: Vec<_> = keys
+        .iter()
+        .filter_map(|key| output.find(&format!("\"{}\":", key)).map(|i| (i, *key)))
+        .collect();
+    found.sort();
+    found.into_iter().map(|(_, key)| key).collect()
+}
+
+const ORDERED_KEYS: [&str; 7] = [
+    "default_a",
+    "default_b",
+    "event_a",
+    "event_b",
+    "span_x",
+    "span_y",
+    "span_z",
+];
+
+#[test]
+fn fields_are_emitted_in_declaration_order() {
+    let output = run_with_field_order(FieldOrder::EventFieldsFirst);
+    assert_eq!(
+        key_order(&output, &ORDERED_KEYS),
+        vec![
+            "default_a",
+            "default_b",
+            "event_b",
+            "event_a",
+            "span_y",
+            "span_x",
+            "span_z"
+        ]
+    );
+    assert_eq!(output.matches("\"span_z\":2").count(), 1);
+
+    let output = run_with_field_order(FieldOrder::SpanFieldsFirst);
+    assert_eq!(
+        key_order(&output, &ORDERED_KEYS),
+        vec![
+            "default_a",
+            "default_b",
+            "span_y",
+            "span_x",
+            "span_z",
+            "event_b",
+            "event_a"
+        ]
+    );
+}
+
+#[test]
+fn fields_can_be_emitted_in_alphabetical_order() {
+    let output = run_with_field_order(FieldOrder::Alphabetical);
+    assert_eq!(key_order(&output, &ORDERED_KEYS), ORDERED_KEYS.to_vec());
+}
+
+/// Adds a field with a computed key to every span, hides the `secret` field and keeps
+/// a running total of the `cost` field.
+struct EnrichmentLayer;
+
+impl<S> tracing_subscriber::Layer<S> for EnrichmentLayer
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    fn on_new_span(
+        &self,
+        _attrs: &tracing::span::Attributes<'_>,
+        id: &tracing::span::Id,
+        ctx: tracing_subscriber::layer::Context<'_, S>,
+    ) {
+        let span = ctx.span(id).unwrap();
+        let mut extensions = span.extensions_mut();
+        let storage = extensions.get_mut::<JsonStorage>().unwrap();
+        storage.insert(format!("{}_tenant", span.name()), "acme");
+        storage.remove("secret");
+        let total_cost =
+            storage.get_u64("total_cost").unwrap_or(0) + storage.get_u64("cost").unwrap_or(0);
+        // `total_cost` is inherited, the span does not have its own value yet.
+        assert_eq!(storage.insert("total_cost", total_cost), None);
+    }
+}
+
+#[test]
+fn downstream_layers_can_enrich_span_fields() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default().and_then(EnrichmentLayer),
+        |builder| builder.span_records(SpanRecords::None),
+        || {
+            let outer = span!(Level::INFO, "outer", secret = "hunter2", cost = 2);
+            let _outer = outer.enter();
+            let inner = span!(Level::INFO, "inner", cost = 3);
+            let _inner = inner.enter();
+            info!("enriched");
+        },
+    );
+
+    let record = &tracing_output[0];
+    assert_eq!(record["outer_tenant"], "acme");
+    assert_eq!(record["inner_tenant"], "acme");
+    assert_eq!(record["cost"], 3);
+    assert_eq!(record["total_cost"], 5);
+    assert!(record.get("secret").is_none());
+}
+
+#[test]
+fn fields_inserted_by_downstream_layers_follow_the_inheritance_policy() {
+    let storage_layer =
+        JsonStorageLayer::default().with_inheritance(FieldInheritance::deny(["outer_tenant"]));
+    let records = run_with_builder_and_get_output(
+        storage_layer.and_then(EnrichmentLayer),
+        |builder| builder.span_records(SpanRecords::None),
+        || {
+            let outer = span!(Level::INFO, "outer", cost = 2);
+            let _outer = outer.enter();
+            info!("outer event");
+            let _inner = span!(Level::INFO, "inner", cost = 3).entered();
+            info!("inner event");
+        },
+    );
+    assert_eq!(records[0]["outer_tenant"], "acme");
+    let inner_event = &records[1];
+    assert_eq!(inner_event["inner_tenant"], "acme");
+    assert_eq!(inner_event["total_cost"], 5);
+    assert!(inner_event.get("outer_tenant").is_none());
+}
+
+#[test]
+fn span_records_can_leave_inherited_fields_out() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_records_own_fields_only(true),
+        || {
+            let outer = span!(Level::INFO, "outer", outer_field = 1);
+            let _outer = outer.enter();
+            let inner = span!(Level::INFO, "inner", inner_field = 2);
+            let _inner = inner.enter();
+            info!("in context");
+        },
+    );
+
+    let inner_start = &tracing_output[1];
+    assert_eq!(inner_start["msg"], "[INNER - START]");
+    assert_eq!(inner_start["inner_field"], 2);
+    assert!(inner_start.get("outer_field").is_none());
+    let event = &tracing_output[2];
+    assert_eq!(event["inner_field"], 2);
+    assert_eq!(event["outer_field"], 1);
+}
+
+#[test]
+fn storage_tells_own_fields_from_inherited_ones() {
+    let sources = Arc::new(Mutex::new(Vec::new()));
+    let filter = {
+        let sources = Arc::clone(&sources);
+        move |metadata: &tracing::Metadata<'_>, fields: &JsonStorage<'_>, ty: &Type| {
+            if metadata.name() == "inner" && matches!(ty, Type::EnterSpan) {
+                let mut sources = sources.lock().unwrap();
+                for (key, _, source) in fields.iter_with_source() {
+                    let source = match source {
+                        FieldSource::Own => "own".to_string(),
+                        FieldSource::Inherited(id) => format!("span {}", id.into_u64()),
+                    };
+                    sources.push((key.to_owned(), source));
+                }
+                assert_eq!(fields.own_fields().count(), 2);
+                assert_eq!(fields.source("missing"), None);
+            }
+            true
+        }
+    };
+    let outer_id = Arc::new(Mutex::new(None));
+    run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_record_filter(filter),
+        || {
+            let outer = span!(Level::INFO, "outer", shared = 1, outer_field = 1);
+            *outer_id.lock().unwrap() = outer.id();
+            let _outer = outer.enter();
+            let _inner = span!(Level::INFO, "inner", shared = 2, inner_field = 2).entered();
+        },
+    );
+
+    let outer_id = outer_id.lock().unwrap().clone().unwrap().into_u64();
+    assert_eq!(
+        *sources.lock().unwrap(),
+        vec![
+            ("outer_field".to_owned(), format!("span {}", outer_id)),
+            ("shared".to_owned(), "own".to_owned()),
+            ("inner_field".to_owned(), "own".to_owned()),
+        ]
+    );
+}
+
+#[test]
+fn fields_are_resolved_once_in_deep_span_trees() {
+    const DEPTH: usize = 200;
+    let fields = Arc::new(Mutex::new(Vec::new()));
+    let filter = {
+        let fields = Arc::clone(&fields);
+        move |metadata: &tracing::Metadata<'_>, storage: &JsonStorage<'_>, ty: &Type| {
+            if metadata.name() == "leaf" && matches!(ty, Type::EnterSpan) {
+                let mut fields = fields.lock().unwrap();
+                for (key, value, source) in storage.iter_with_source() {
+                    let own = source == FieldSource::Own;
+                    fields.push((key.to_owned(), value.clone(), own));
+                }
+            }
+            true
+        }
+    };
+    run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.span_record_filter(filter),
+        || {
+            let mut spans = Vec::with_capacity(DEPTH);
+            for depth in 0..DEPTH {
+                let span = span!(
+                    Level::INFO,
+                    "node",
+                    depth,
+                    root = depth == 0,
+                    middle = tracing::field::Empty
+                );
+                if depth == DEPTH / 2 {
+                    span.record("middle", depth);
+                }
+                spans.push(span.entered());
+            }
+            let _leaf = span!(Level::INFO, "leaf", depth = DEPTH).entered();
+        },
+    );
+
+    // Each field comes from the closest span having it, in the order of the spans.
+    assert_eq!(
+        *fields.lock().unwrap(),
+        vec![
+            ("middle".to_owned(), json!(DEPTH / 2), false),
+            ("root".to_owned(), json!(false), false),
+            ("depth".to_owned(), json!(DEPTH), true),
+        ]
+    );
+}
+
+fn inheritance_action() {
+    let outer = span!(Level::INFO, "outer", request_id = 1, payload = "large");
+    let _outer = outer.enter();
+    info!("outer event");
+    let _inner = span!(Level::INFO, "inner", step = 2).entered();
+    info!("inner event");
+}
+
+#[test]
+fn field_inheritance_can_be_turned_off() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default().with_inheritance(FieldInheritance::None),
+        |builder| builder.span_records(SpanRecords::None),
+        inheritance_action,
+    );
+
+    // Events still get the fields of their span.
+    assert_eq!(tracing_output[0]["request_id"], 1);
+    assert_eq!(tracing_output[0]["payload"], "large");
+    let inner_event = &tracing_output[1];
+    assert_eq!(inner_event["step"], 2);
+    assert!(inner_event.get("request_id").is_none());
+    assert!(inner_event.get("payload").is_none());
+}
+
+#[test]
+fn inherited_fields_can_be_allowed_or_denied_by_name() {
+    for inheritance in [
+        FieldInheritance::allow(["request_id"]),
+        FieldInheritance::deny(["payload"]),
+    ] {
+        let tracing_output = run_with_builder_and_get_output(
+            JsonStorageLayer::default().with_inheritance(inheritance),
+            |builder| builder.span_records(SpanRecords::None),
+            inheritance_action,
+        );
+
+        assert_eq!(tracing_output[0]["payload"], "large");
+        let inner_event = &tracing_output[1];
+        assert_eq!(inner_event["step"], 2);
+        assert_eq!(inner_event["request_id"], 1);
+        assert!(inner_event.get("payload").is_none());
+    }
+}
+
+#[test]
+fn fields_can_be_kept_local_to_their_span() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default().with_local_field_prefix("local."),
+        |builder| builder.span_records(SpanRecords::None),
+        || {
+            let outer = span!(
+                Level::INFO,
+                "outer",
+                shared = "outer",
+                local.payload = "large",
+                late = tracing::field::Empty
+            );
+            let _outer = outer.enter();
+            let inner = span!(
+                Level::INFO,
+                "inner",
+                payload = "small",
+                local.shared = "inner"
+            );
+            let _inner = inner.enter();
+            info!("inner event");
+            let _innermost = span!(Level::INFO, "innermost").entered();
+            info!("innermost event");
+            drop(_innermost);
+            drop(_inner);
+            outer.record("late", "recorded");
+            info!(local.attempt = 1, "outer event");
+        },
+    );
+
+    let inner_event = &tracing_output[0];
+    assert_eq!(inner_event["payload"], "small");
+    assert_eq!(inner_event["shared"], "inner");
+    assert!(inner_event.get("local.shared").is_none());
+    // `shared` is local to `inner`: `innermost` inherits the value of `outer`.
+    let innermost_event = &tracing_output[1];
+    assert_eq!(innermost_event["payload"], "small");
+    assert_eq!(innermost_event["shared"], "outer");
+    let outer_event = &tracing_output[2];
+    assert_eq!(outer_event["payload"], "large");
+    assert_eq!(outer_event["late"], "recorded");
+    assert!(outer_event.get("local.payload").is_none());
+    // The prefix only applies to span fields.
+    assert_eq!(outer_event["local.attempt"], 1);
+}
+
+#[test]
+fn records_can_be_emitted_when_spans_are_entered_and_exited() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| builder.enter_exit_records(EnterExitRecords::All),
+        || {
+            let span = span!(Level::INFO, "polled", task = 1);
+            span.in_scope(|| ());
+            span.in_scope(|| info!("polled again"));
+        },
+    );
+
+    assert_eq!(
+        messages(&tracing_output),
+        [
+            "[POLLED - START]",
+            "[POLLED - ENTER]",
+            "[POLLED - EXIT]",
+            "[POLLED - ENTER]",
+            "[POLLED - EVENT] polled again",
+            "[POLLED - EXIT]",
+            "[POLLED - END]"
+        ]
+    );
+    for record in &tracing_output {
+        assert_eq!(record["task"], 1);
+    }
+}
+
+#[test]
+fn enter_and_exit_records_can_be_rate_limited() {
+    let tracing_output = run_with_builder_and_get_output(
+        JsonStorageLayer::default(),
+        |builder| {
+            builder
+                .clock(TickingClock::new())
+                .span_records(SpanRecords::None)
+                .enter_exit_records(EnterExitRecords::RateLimited { max_per_second: 1 })
+        },
+        || {
+            let first = span!(Level::INFO, "first");
+            // The nested enter is over the budget: it is skipped, along with its exit.
+            first.in_scope(|| first.in_scope(|| ()));
+            span!(Level::INFO, "second").in_scope(|| ());
+        },
+    );
+
+    assert_eq!(
+        messages(&tracing_output),
+        ["[FIRST - ENTER]", "[FIRST - EXIT]"]
+    );
+}
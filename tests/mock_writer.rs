@@ -1,5 +1,6 @@
 use std::io;
-use std::sync::{Mutex, MutexGuard, TryLockError};
+use std::sync::{Arc, Mutex, MutexGuard, TryLockError};
+use tracing_subscriber::fmt::MakeWriter;
 
 /// Use a vector of bytes behind a Mutex as writer in order to inspect the tracing output
 /// for testing purposes.
@@ -34,3 +35,54 @@ impl<'a> io::Write for MockWriter<'a> {
         self.buf()?.flush()
     }
 }
+
+/// A `MakeWriter` handing out `MockWriter`s that all write to the same in-memory buffer.
+///
+/// Each instance owns its own buffer, so tests using it can safely run in parallel.
+#[derive(Clone, Default)]
+pub struct MockMakeWriter {
+    buf: Arc<Mutex<Vec<u8>>>,
+}
+
+impl MockMakeWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Get the formatted output written so far.
+    pub fn get_string(&self) -> String {
+        String::from_utf8(self.buf.lock().unwrap().to_vec()).unwrap()
+    }
+}
+
+impl<'a> MakeWriter<'a> for MockMakeWriter {
+    type Writer = MockWriter<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        MockWriter::new(&self.buf)
+    }
+}
+
+/// A `MakeWriter` handing out writers which fail every write.
+#[derive(Clone, Copy, Default)]
+pub struct FailingMakeWriter;
+
+pub struct FailingWriter;
+
+impl io::Write for FailingWriter {
+    fn write(&mut self, _buf: &[u8]) -> io::Result<usize> {
+        Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken pipe"))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for FailingMakeWriter {
+    type Writer = FailingWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        FailingWriter
+    }
+}
@@ -0,0 +1,194 @@
+use crate::mock_writer::MockMakeWriter;
+use serde_json::Value;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use tracing::info;
+use tracing_bunyan_formatter::{
+    BunyanFormattingLayer, JsonStorageLayer, NonBlocking, OverflowPolicy,
+};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+// Not every test binary uses every helper.
+#[allow(dead_code)]
+mod mock_writer;
+
+/// A `MakeWriter` which lets the test hold the worker thread of the non-blocking writer
+/// until the queue is in the state it wants to check.
+struct GatedMakeWriter {
+    inner: MockMakeWriter,
+    gate: Arc<Mutex<()>>,
+    entered: Mutex<Sender<()>>,
+}
+
+impl<'a> MakeWriter<'a> for GatedMakeWriter {
+    type Writer = <MockMakeWriter as MakeWriter<'a>>::Writer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        let _ = self.entered.lock().unwrap().send(());
+        drop(self.gate.lock().unwrap());
+        self.inner.make_writer()
+    }
+}
+
+fn gated_make_writer() -> (
+    GatedMakeWriter,
+    MockMakeWriter,
+    Arc<Mutex<()>>,
+    Receiver<()>,
+) {
+    let inner = MockMakeWriter::new();
+    let gate = Arc::new(Mutex::new(()));
+    let (sender, receiver) = mpsc::channel();
+    let make_writer = GatedMakeWriter {
+        inner: inner.clone(),
+        gate: Arc::clone(&gate),
+        entered: Mutex::new(sender),
+    };
+    (make_writer, inner, gate, receiver)
+}
+
+/// A `MakeWriter` which lets the test hand out permits to the worker thread of the
+/// non-blocking writer, one per record, until the permits are dropped.
+struct PacedMakeWriter {
+    inner: MockMakeWriter,
+    permits: Mutex<Receiver<()>>,
+    entered: Mutex<Sender<()>>,
+}
+
+impl<'a> MakeWriter<'a> for PacedMakeWriter {
+    type Writer = <MockMakeWriter as MakeWriter<'a>>::Writer;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        let _ = self.entered.lock().unwrap().send(());
+        let _ = self.permits.lock().unwrap().recv();
+        self.inner.make_writer()
+    }
+}
+
+fn messages(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|line| {
+            serde_json::from_str::<Value>(line).unwrap()["msg"]
+                .as_str()
+                .unwrap()
+                .to_owned()
+        })
+        .collect()
+}
+
+// Emit the messages "1" to "4" via a non-blocking writer with room for two records,
+// while its worker thread is stuck writing "1".
+fn run_with_full_queue(overflow_policy: OverflowPolicy) -> (Vec<String>, u64) {
+    let (make_writer, output, gate, entered) = gated_make_writer();
+    let (non_blocking, guard) = NonBlocking::builder()
+        .buffered_records(2)
+        .overflow_policy(overflow_policy)
+        .finish(make_writer);
+    let subscriber =
+        Registry::default()
+            .with(JsonStorageLayer::default())
+            .with(BunyanFormattingLayer::new(
+                "test".into(),
+                non_blocking.clone(),
+            ));
+
+    tracing::subscriber::with_default(subscriber, || {
+        let held = gate.lock().unwrap();
+        info!("1");
+        entered.recv().unwrap();
+        info!("2");
+        info!("3");
+        info!("4");
+        drop(held);
+    });
+    drop(guard);
+
+    (
+        messages(&output.get_string()),
+        non_blocking.dropped_records(),
+    )
+}
+
+#[test]
+fn queued_records_are_written_when_the_guard_is_dropped() {
+    let output = MockMakeWriter::new();
+    let (non_blocking, guard) = NonBlocking::builder()
+        .buffered_records(1)
+        .overflow_policy(OverflowPolicy::Block)
+        .finish(output.clone());
+    let subscriber =
+        Registry::default()
+            .with(JsonStorageLayer::default())
+            .with(BunyanFormattingLayer::new(
+                "test".into(),
+                non_blocking.clone(),
+            ));
+
+    tracing::subscriber::with_default(subscriber, || {
+        for i in 0..100 {
+            info!("{}", i);
+        }
+    });
+    drop(guard);
+
+    let expected: Vec<String> = (0..100).map(|i| i.to_string()).collect();
+    assert_eq!(messages(&output.get_string()), expected);
+    assert_eq!(non_blocking.dropped_records(), 0);
+}
+
+#[test]
+fn records_are_taken_off_the_queue_one_at_a_time() {
+    let output = MockMakeWriter::new();
+    let (permit, permits) = mpsc::channel();
+    let (entered_sender, entered) = mpsc::channel();
+    let make_writer = PacedMakeWriter {
+        inner: output.clone(),
+        permits: Mutex::new(permits),
+        entered: Mutex::new(entered_sender),
+    };
+    let (non_blocking, guard) = NonBlocking::builder()
+        .buffered_records(2)
+        .overflow_policy(OverflowPolicy::DropOldest)
+        .finish(make_writer);
+    let subscriber =
+        Registry::default()
+            .with(JsonStorageLayer::default())
+            .with(BunyanFormattingLayer::new(
+                "test".into(),
+                non_blocking.clone(),
+            ));
+
+    tracing::subscriber::with_default(subscriber, || {
+        info!("1");
+        entered.recv().unwrap();
+        info!("2");
+        info!("3");
+        permit.send(()).unwrap();
+        // The worker thread is writing "2": only "3" is still waiting.
+        entered.recv().unwrap();
+        info!("4");
+        info!("5");
+        drop(permit);
+    });
+    drop(guard);
+
+    assert_eq!(messages(&output.get_string()), vec!["1", "2", "4", "5"]);
+    assert_eq!(non_blocking.dropped_records(), 1);
+}
+
+#[test]
+fn newest_records_are_dropped_when_the_queue_is_full() {
+    let (messages, dropped_records) = run_with_full_queue(OverflowPolicy::DropNewest);
+    assert_eq!(messages, vec!["1", "2", "3"]);
+    assert_eq!(dropped_records, 1);
+}
+
+#[test]
+fn oldest_records_are_dropped_when_the_queue_is_full() {
+    let (messages, dropped_records) = run_with_full_queue(OverflowPolicy::DropOldest);
+    assert_eq!(messages, vec!["1", "3", "4"]);
+    assert_eq!(dropped_records, 1);
+}
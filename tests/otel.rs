@@ -0,0 +1,68 @@
+#![cfg(feature = "otel")]
+use crate::mock_writer::MockMakeWriter;
+use opentelemetry::trace::{TraceContextExt, TracerProvider};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use serde_json::{json, Value};
+use tracing::{info, info_span};
+use tracing_bunyan_formatter::{BuildError, BunyanFormattingLayer, JsonStorageLayer};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::Registry;
+
+// Not every test binary uses every helper.
+#[allow(dead_code)]
+mod mock_writer;
+
+#[test]
+fn records_carry_the_opentelemetry_context() {
+    let provider = SdkTracerProvider::builder().build();
+    let make_writer = MockMakeWriter::new();
+    let formatting_layer = BunyanFormattingLayer::builder("test".into(), make_writer.clone())
+        .otel_context(true)
+        .build()
+        .unwrap();
+    let subscriber = Registry::default()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")))
+        .with(JsonStorageLayer::default())
+        .with(formatting_layer);
+
+    let (trace_id, span_id) = tracing::subscriber::with_default(subscriber, || {
+        let span = info_span!("request");
+        let _enter = span.enter();
+        info!("handling request");
+
+        let context = span.context();
+        let span_context = context.span().span_context().clone();
+        (
+            span_context.trace_id().to_string(),
+            span_context.span_id().to_string(),
+        )
+    });
+
+    let records: Vec<Value> = make_writer
+        .get_string()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records.len(), 3);
+    // The event and the END record of the span.
+    for record in &records[1..] {
+        assert_eq!(record["trace_id"], json!(trace_id));
+        assert_eq!(record["span_id"], json!(span_id));
+    }
+    assert_eq!(trace_id.len(), 32);
+    assert_eq!(span_id.len(), 16);
+}
+
+#[test]
+fn otel_context_and_span_ids_are_mutually_exclusive() {
+    let result = BunyanFormattingLayer::builder("test".into(), MockMakeWriter::new())
+        .otel_context(true)
+        .span_ids(true)
+        .build();
+
+    assert_eq!(
+        result.err(),
+        Some(BuildError::ConflictingOptions("span_ids", "otel_context"))
+    );
+}